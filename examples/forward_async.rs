@@ -0,0 +1,38 @@
+// Run the forwarding loop as a task on a tokio runtime.
+//
+// A datagram written to the local socket comes out of the outside socket
+// with IP and UDP headers, then the loop is stopped through its channel.
+//
+//   cargo run --example forward_async --features tokio
+
+use std::net::Ipv4Addr;
+
+use tokio::net::UnixDatagram;
+use tokio::sync::oneshot;
+use tunnel_inserter::{forward_async, ForwardOptions, ForwardStats, PortPair};
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+  let (outside, peer) = UnixDatagram::pair().unwrap();
+  let (local, app) = UnixDatagram::pair().unwrap();
+  let (stop, shutdown) = oneshot::channel();
+
+  let task = tokio::spawn(async move {
+    let stats = ForwardStats::default();
+    let pairs = [PortPair { local: 2000, remote: 3000 }];
+    let remotes = [Ipv4Addr::new(10, 0, 0, 2).into()];
+    let opts = ForwardOptions::default();
+    let local_addr = Ipv4Addr::new(10, 0, 0, 1).into();
+    forward_async(&outside, shutdown, local_addr, &remotes, &pairs, &[local], &stats, &opts).await;
+    stats
+  });
+
+  app.send(b"hello").await.unwrap();
+  let mut buf = [0u8; 1500];
+  let n = peer.recv(&mut buf).await.unwrap();
+  println!("{n} bytes on the outside: {:02x?}", &buf[..n]);
+
+  stop.send(()).unwrap();
+  let stats = task.await.unwrap();
+  println!("{:?}", stats.pair_snapshot());
+}
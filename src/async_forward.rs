@@ -0,0 +1,299 @@
+// Forwarding loop for tokio based hosts.
+
+use std::future::poll_fn;
+use std::io::ErrorKind;
+use std::net::IpAddr;
+use std::task::Poll;
+use std::time::Instant;
+
+use log::{debug, error, info, warn};
+use tokio::net::UnixDatagram;
+use tokio::sync::oneshot;
+
+use crate::forward::{describe_pairs, suppressed_note, Delivery, ForwardEngine, ForwardOptions, LogLimiter, PortPair};
+use crate::stats::{bump, bump_by, ForwardStats, PairStats};
+
+/// Failed receives in a row after which a local socket is no longer polled.
+/// A pending socket error is cleared by the receive reporting it, so only an
+/// error that keeps coming back gets there.
+const MAX_RECV_ERRORS: u32 = 3;
+
+/// Forward between the outside socket and the local `sockets` on the current
+/// tokio runtime until `shutdown` fires or its sender is dropped.
+///
+/// Does the same encapsulation and matching as [`crate::forward`] through a
+/// [`ForwardEngine`].  Packets which don't fit into a full socket are dropped
+/// rather than queued, `send_backlog`, `zero_copy`, `panic_dump` and the
+/// control pipe have no equivalent here.  A local socket whose receives keep
+/// failing is no longer polled.
+#[allow(clippy::too_many_arguments)]
+pub async fn forward_async(
+  outside: &UnixDatagram,
+  shutdown: oneshot::Receiver<()>,
+  local_addr: IpAddr,
+  remote_addrs: &[IpAddr], // one per port pair
+  port_pairs: &[PortPair],
+  sockets: &[UnixDatagram], // local sockets
+  stats: &ForwardStats,
+  opts: &ForwardOptions,
+) {
+  assert_eq!(port_pairs.len(), sockets.len());
+  let mut engine = ForwardEngine::new(local_addr, remote_addrs, port_pairs, stats, opts);
+  let pair_stats = stats.pairs(port_pairs.len());
+  let labels = describe_pairs(port_pairs, &opts.pair_names);
+  let stop_at = opts.max_runtime.and_then(|d| Instant::now().checked_add(d));
+  // One more byte than allowed, to tell an oversized datagram from a full one.
+  let mut buf = vec![0u8; opts.max_datagram + 1];
+  let mut obuf = vec![0u8; 65536];
+  // Failed receives in a row on each local socket, `None` once given up on.
+  let mut recv_errors = vec![Some(0); sockets.len()];
+  let mut recv_error_log = LogLimiter::default();
+  tokio::pin!(shutdown);
+
+  loop {
+    let mut stop = false;
+    let wake = engine.next_deadline().into_iter().chain(stop_at).min();
+    tokio::select! {
+      _ = &mut shutdown => {
+        info!("Shutdown requested");
+        stop = true;
+      }
+      res = outside.recv(&mut obuf) => match res {
+        Ok(len) => {
+          for d in engine.handle_outside(&obuf[..len]) {
+            match d {
+              Delivery::Local(idx, data) => send_local(sockets, &labels, &pair_stats, idx, &data),
+              Delivery::Outside(pkt) => send_outside(outside, stats, None, &pkt),
+            }
+          }
+        }
+        Err(e) => {
+          error!("error when receiving from outside: {e}");
+          stop = true;
+        }
+      },
+      _ = poll_fn(|cx| {
+        let mut polled = sockets.iter().zip(&recv_errors).filter(|(_, errs)| errs.is_some());
+        match polled.any(|(s, _)| s.poll_recv_ready(cx).is_ready()) {
+          true => Poll::Ready(()),
+          false => Poll::Pending,
+        }
+      }) => {
+        // Drain every ready socket, up to a batch each so none starves.
+        for (idx, sock) in sockets.iter().enumerate() {
+          for _ in 0..opts.recv_batch.max(1) {
+            let Some(errs) = &mut recv_errors[idx] else {
+              break;
+            };
+            let len = match sock.try_recv(&mut buf) {
+              Ok(len) => len,
+              Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                *errs = 0;
+                break;
+              }
+              // Keep going, the next receive either clears the readiness or
+              // shows the error to stay.
+              Err(e) => {
+                bump(&stats.recv_errors);
+                *errs += 1;
+                if *errs >= MAX_RECV_ERRORS {
+                  warn!("Receiving from {} keeps failing: {e}, no longer polling it", labels[idx]);
+                  bump(&stats.fd_faults);
+                  recv_errors[idx] = None;
+                } else if let Some(more) = recv_error_log.hit(Instant::now()) {
+                  warn!("error when receiving from fd{idx}: {e}{}", suppressed_note(more));
+                }
+                continue;
+              }
+            };
+            *errs = 0;
+            if len > opts.max_datagram {
+              debug!("Oversized datagram on fd{idx}");
+              bump(&stats.oversize_drops);
+              continue;
+            }
+            for pkt in engine.handle_local(idx, &buf[..len]) {
+              send_outside(outside, stats, Some((&labels[idx], &pair_stats[idx])), &pkt);
+            }
+          }
+        }
+      }
+      _ = sleep_until(wake) => {}
+    }
+    let now = Instant::now();
+    if !stop && stop_at.is_some_and(|t| t <= now) {
+      info!("Maximum runtime reached, shutting down");
+      stop = true;
+    }
+    // Send the frames whose time is up, or all of them when stopping.
+    for pkt in engine.handle_timers(now, stop) {
+      send_outside(outside, stats, None, &pkt);
+    }
+    if stop {
+      break;
+    }
+  }
+  if let Some(w) = &opts.pcap {
+    if let Err(e) = w.lock().unwrap().flush() {
+      warn!("Can't write the packet trace: {e}");
+    }
+  }
+}
+
+async fn sleep_until(deadline: Option<Instant>) {
+  match deadline {
+    Some(d) => tokio::time::sleep_until(d.into()).await,
+    None => std::future::pending().await,
+  }
+}
+
+fn send_local(sockets: &[UnixDatagram], labels: &[String], pairs: &[PairStats], idx: usize, data: &[u8]) {
+  match sockets[idx].try_send(data) {
+    Ok(_) => {
+      bump(&pairs[idx].packets_from_outside);
+      bump_by(&pairs[idx].bytes_from_outside, data.len() as u64);
+    }
+    Err(e) => {
+      match e.kind() {
+        ErrorKind::WouldBlock => debug!("drop when sending to {}", labels[idx]),
+        _ => error!("error when sending to {}: {e}", labels[idx]),
+      }
+      bump(&pairs[idx].drops_inside);
+    }
+  }
+}
+
+/// Send `pkt` to the outside, or drop it.  `pair` is the port pair it came
+/// from, as logged and its counters, none for frames and echoes.
+fn send_outside(outside: &UnixDatagram, stats: &ForwardStats, pair: Option<(&str, &PairStats)>, pkt: &[u8]) {
+  let Err(e) = outside.try_send(pkt) else {
+    return;
+  };
+  if e.kind() == ErrorKind::WouldBlock {
+    bump(&stats.outside_full);
+  } else {
+    error!("Sending to outside failed: {e}");
+  }
+  match pair {
+    Some((label, p)) => {
+      debug!("drop when sending to outside from {label}");
+      bump(&p.drops_outside);
+    }
+    None => debug!("drop when sending to outside"),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::forward_async;
+  use crate::forward::{ForwardOptions, PortPair};
+  use crate::frame::{parse_frame, Coalesce};
+  use crate::stats::ForwardStats;
+  use crate::udp::{create_ipv4_udp_packet, parse_ipv4_udp_packet};
+  use std::net::Ipv4Addr;
+  use std::os::fd::AsRawFd;
+  use std::time::Duration;
+  use tokio::net::UnixDatagram;
+  use tokio::sync::oneshot;
+
+  const LOCAL: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+  const REMOTE: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+
+  #[tokio::test]
+  async fn forwards_both_ways_over_tokio_sockets() {
+    let stats = ForwardStats::default();
+    let (outside, peer) = UnixDatagram::pair().unwrap();
+    let (local, app) = UnixDatagram::pair().unwrap();
+    let pairs = [PortPair { local: 2000, remote: 3000 }];
+    let opts = ForwardOptions::default();
+    let (stop, shutdown) = oneshot::channel();
+    let sockets = [local];
+
+    let remotes = [REMOTE.into()];
+    let run = forward_async(&outside, shutdown, LOCAL.into(), &remotes, &pairs, &sockets, &stats, &opts);
+    let drive = async {
+      app.send(b"out").await.unwrap();
+      let mut buf = [0u8; 1500];
+      let n = peer.recv(&mut buf).await.unwrap();
+      let parsed = parse_ipv4_udp_packet(&buf[..n]).unwrap();
+      assert_eq!((parsed.src_port, parsed.dst_port, parsed.payload), (2000, 3000, &b"out"[..]));
+
+      peer.send(&create_ipv4_udp_packet(b"in", REMOTE, LOCAL, 3000, 2000)).await.unwrap();
+      let n = app.recv(&mut buf).await.unwrap();
+      assert_eq!(&buf[..n], b"in");
+      stop.send(()).unwrap();
+    };
+    tokio::join!(run, drive);
+    let pair = stats.pair_snapshot()[0];
+    assert_eq!((pair.packets_to_outside, pair.packets_from_outside), (1, 1));
+  }
+
+  #[tokio::test]
+  async fn survives_recv_errors() {
+    let stats = ForwardStats::default();
+    let (outside, peer) = UnixDatagram::pair().unwrap();
+    let (local, app) = std::os::unix::net::UnixDatagram::pair().unwrap();
+    let pairs = [PortPair { local: 2000, remote: 3000 }];
+    let opts = ForwardOptions::default();
+    let (stop, shutdown) = oneshot::channel();
+    // Disconnecting the far end with an unread datagram leaves ECONNRESET
+    // pending on the loop's end, reported ahead of the datagram queued there.
+    local.send(b"unread").unwrap();
+    app.send(b"queued").unwrap();
+    let unspec = libc::sockaddr {
+      sa_family: libc::AF_UNSPEC as libc::sa_family_t,
+      sa_data: [0; 14],
+    };
+    let len = std::mem::size_of::<libc::sockaddr>() as libc::socklen_t;
+    // SAFETY: a valid sockaddr of the given length on a descriptor we own.
+    assert_eq!(unsafe { libc::connect(app.as_raw_fd(), &unspec, len) }, 0);
+    local.set_nonblocking(true).unwrap();
+    let sockets = [UnixDatagram::from_std(local).unwrap()];
+
+    let remotes = [REMOTE.into()];
+    let run = forward_async(&outside, shutdown, LOCAL.into(), &remotes, &pairs, &sockets, &stats, &opts);
+    let drive = async {
+      let mut buf = [0u8; 1500];
+      let n = peer.recv(&mut buf).await.unwrap();
+      assert_eq!(parse_ipv4_udp_packet(&buf[..n]).unwrap().payload, b"queued");
+      stop.send(()).unwrap();
+    };
+    tokio::join!(run, drive);
+    let snap = stats.snapshot();
+    assert_eq!((snap.recv_errors, snap.fd_faults), (1, 0));
+  }
+
+  #[test]
+  fn flushes_frames_on_shutdown() {
+    let stats = ForwardStats::default();
+    let rt = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+    rt.block_on(async {
+      let (outside, peer) = UnixDatagram::pair().unwrap();
+      let (local, app) = UnixDatagram::pair().unwrap();
+      let pairs = [PortPair { local: 2000, remote: 3000 }];
+      // Nothing would send the frame before the shutdown.
+      let opts = ForwardOptions {
+        coalesce: Some(Coalesce {
+          max_bytes: 1400,
+          max_delay: Duration::from_secs(3600),
+        }),
+        ..Default::default()
+      };
+      let (stop, shutdown) = oneshot::channel();
+      let sockets = [local];
+
+      let remotes = [REMOTE.into()];
+      let run = forward_async(&outside, shutdown, LOCAL.into(), &remotes, &pairs, &sockets, &stats, &opts);
+      let drive = async {
+        app.send(b"half").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        stop.send(()).unwrap();
+      };
+      tokio::join!(run, drive);
+      let mut buf = [0u8; 1500];
+      let n = peer.recv(&mut buf).await.unwrap();
+      let frame = parse_ipv4_udp_packet(&buf[..n]).unwrap().payload;
+      assert_eq!(parse_frame(frame).unwrap(), vec![&b"half"[..]]);
+    });
+  }
+}
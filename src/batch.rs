@@ -0,0 +1,305 @@
+use nix::errno::Errno;
+use nix::sys::socket::{recvmmsg, sendmmsg, ControlMessage, MsgFlags, MultiHeaders};
+use std::convert::Infallible;
+use std::io::{IoSlice, IoSliceMut};
+use std::os::fd::AsRawFd;
+
+/// Preallocated state for gathering up to `batch` datagrams per `recvmmsg`
+/// call.  The headers and the receive buffers are reused across calls.
+pub struct RecvBatch {
+  headers: MultiHeaders<()>,
+  bufs: Vec<Vec<u8>>,
+  /// Length of each received datagram, `None` if it was truncated.
+  lens: Vec<Option<usize>>,
+  truncated: usize,
+}
+
+impl RecvBatch {
+  pub fn new(batch: usize, buf_size: usize) -> Self {
+    assert!(batch >= 1, "recv batch must be at least 1");
+    Self {
+      headers: MultiHeaders::preallocate(batch, None),
+      bufs: vec![vec![0u8; buf_size]; batch],
+      lens: Vec::with_capacity(batch),
+      truncated: 0,
+    }
+  }
+
+  /// Receive up to `batch` datagrams from `sock` in a single system call and
+  /// return how many were received, including the ones which did not fit
+  /// into a buffer.  Those are left out of [`RecvBatch::iter`] and counted by
+  /// [`RecvBatch::truncated`].
+  pub fn recv(&mut self, sock: &(impl AsRawFd + ?Sized)) -> nix::Result<usize> {
+    let mut iovs: Vec<[IoSliceMut; 1]> = self
+      .bufs
+      .iter_mut()
+      .map(|b| [IoSliceMut::new(&mut b[..])])
+      .collect();
+    self.lens.clear();
+    self.truncated = 0;
+    let msgs = recvmmsg(
+      sock.as_raw_fd(),
+      &mut self.headers,
+      iovs.iter_mut(),
+      MsgFlags::MSG_DONTWAIT,
+      None,
+    )?;
+    for m in msgs {
+      if m.flags.contains(MsgFlags::MSG_TRUNC) {
+        self.truncated += 1;
+        self.lens.push(None);
+      } else {
+        self.lens.push(Some(m.bytes));
+      }
+    }
+    Ok(self.lens.len())
+  }
+
+  /// Datagrams dropped by the last call to [`RecvBatch::recv`] because they
+  /// were larger than the buffers.
+  pub fn truncated(&self) -> usize {
+    self.truncated
+  }
+
+  /// Datagrams gathered by the last call to [`RecvBatch::recv`].
+  pub fn iter(&self) -> impl Iterator<Item = &[u8]> {
+    self.bufs.iter().zip(&self.lens).filter_map(|(b, &l)| Some(&b[..l?]))
+  }
+}
+
+/// Outgoing packets queued for a single socket and flushed with `sendmmsg`,
+/// at most `batch` packets per call.  Up to `backlog` packets the socket has
+/// no room for stay queued for the next flush.
+pub struct SendBatch {
+  headers: MultiHeaders<()>,
+  addrs: Vec<Option<()>>,
+  /// Packet buffers, the first `queued` of them holding queued packets.  The
+  /// others are kept for reuse, so that building packets in place does not
+  /// allocate once every buffer has grown to size.
+  pkts: Vec<Vec<u8>>,
+  queued: usize,
+  /// Port pair of each queued packet, if it belongs to one.
+  pairs: Vec<Option<usize>>,
+  /// Port pairs of the packets dropped by the last flush.
+  unsent: Vec<Option<usize>>,
+  batch: usize,
+  backlog: usize,
+}
+
+impl SendBatch {
+  pub fn new(batch: usize, backlog: usize) -> Self {
+    assert!(batch >= 1, "send batch must be at least 1");
+    Self {
+      headers: MultiHeaders::preallocate(batch, None),
+      addrs: vec![None; batch],
+      pkts: Vec::with_capacity(batch),
+      queued: 0,
+      pairs: Vec::with_capacity(batch),
+      unsent: Vec::new(),
+      batch,
+      backlog,
+    }
+  }
+
+  /// Queue a packet which `build` writes into an empty, reused buffer.  A
+  /// packet on behalf of port pair `pair` shows up in [`SendBatch::unsent`]
+  /// if it gets dropped.
+  pub fn push_with(&mut self, pair: Option<usize>, build: impl FnOnce(&mut Vec<u8>)) {
+    let Ok(()) = self.try_push_with(pair, |buf| {
+      build(buf);
+      Ok::<_, Infallible>(())
+    });
+  }
+
+  /// Like [`SendBatch::push_with`], but nothing is queued if `build` fails.
+  pub fn try_push_with<E>(
+    &mut self,
+    pair: Option<usize>,
+    build: impl FnOnce(&mut Vec<u8>) -> Result<(), E>,
+  ) -> Result<(), E> {
+    if self.queued == self.pkts.len() {
+      self.pkts.push(Vec::new());
+    }
+    let buf = &mut self.pkts[self.queued];
+    buf.clear();
+    build(buf)?;
+    self.queued += 1;
+    self.pairs.push(pair);
+    Ok(())
+  }
+
+  /// Port pair and length of each queued packet, in sending order.
+  pub fn queued(&self) -> impl Iterator<Item = (Option<usize>, usize)> + '_ {
+    self.pairs.iter().copied().zip(self.pkts[..self.queued].iter().map(Vec::len))
+  }
+
+  pub fn is_full(&self) -> bool {
+    self.queued >= self.batch
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.queued == 0
+  }
+
+  /// Send the queued packets to `sock`, `batch` at a time.  If the socket is
+  /// full, the oldest `backlog` packets it did not take stay queued, in
+  /// order; other packets that were not sent are dropped.  Returns the number
+  /// of packets handed to the kernel, or the error which stopped the flush.
+  pub fn flush(&mut self, sock: &(impl AsRawFd + ?Sized)) -> Result<usize, (usize, nix::Error)> {
+    let no_cmsgs: [ControlMessage; 0] = [];
+    let mut sent = 0;
+    let mut result = Ok(());
+    while sent < self.queued {
+      let chunk = &self.pkts[sent..self.queued.min(sent + self.batch)];
+      let iovs: Vec<[IoSlice; 1]> = chunk.iter().map(|p| [IoSlice::new(p)]).collect();
+      match sendmmsg(
+        sock.as_raw_fd(),
+        &mut self.headers,
+        iovs.iter(),
+        &self.addrs,
+        no_cmsgs,
+        // EAGAIN rather than blocking, even on a blocking socket.
+        MsgFlags::MSG_DONTWAIT,
+      ) {
+        Ok(res) => sent += res.count(),
+        Err(e) => {
+          result = Err(e);
+          break;
+        }
+      }
+    }
+    let keep = match result {
+      Err(Errno::EAGAIN) => (self.queued - sent).min(self.backlog),
+      _ => 0,
+    };
+    self.pkts[..self.queued].rotate_left(sent);
+    self.pairs.drain(..sent);
+    self.unsent.clear();
+    self.unsent.extend(self.pairs.drain(keep..));
+    self.queued = keep;
+    result.map(|_| sent).map_err(|e| (sent, e))
+  }
+
+  /// Port pairs of the packets the last [`SendBatch::flush`] dropped, `None`
+  /// for packets queued with plain [`SendBatch::push`].
+  pub fn unsent(&self) -> &[Option<usize>] {
+    &self.unsent
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{RecvBatch, SendBatch};
+  use nix::errno::Errno;
+  use std::os::unix::net::UnixDatagram;
+
+  #[test]
+  fn recv_batch_gathers_at_most_batch() {
+    let (a, b) = UnixDatagram::pair().unwrap();
+    for j in 0..5u8 {
+      a.send(&[j; 3]).unwrap();
+    }
+    let mut batch = RecvBatch::new(2, 64);
+    assert_eq!(batch.recv(&b).unwrap(), 2);
+    assert_eq!(batch.iter().collect::<Vec<_>>(), vec![&[0u8; 3], &[1u8; 3]]);
+    assert_eq!(batch.recv(&b).unwrap(), 2);
+    assert_eq!(batch.recv(&b).unwrap(), 1);
+    assert_eq!(batch.iter().collect::<Vec<_>>(), vec![&[4u8; 3]]);
+  }
+
+  #[test]
+  fn recv_batch_drops_truncated() {
+    let (a, b) = UnixDatagram::pair().unwrap();
+    a.send(&[1; 8]).unwrap();
+    a.send(&[2; 9]).unwrap();
+    a.send(&[3; 2]).unwrap();
+    let mut batch = RecvBatch::new(4, 8);
+    assert_eq!(batch.recv(&b).unwrap(), 3);
+    assert_eq!(batch.truncated(), 1);
+    assert_eq!(batch.iter().collect::<Vec<_>>(), vec![&[1u8; 8][..], &[3u8; 2]]);
+  }
+
+  #[test]
+  fn recv_batch_reuses_its_buffers() {
+    let (a, b) = UnixDatagram::pair().unwrap();
+    let mut batch = RecvBatch::new(4, 64);
+    let mut first = None;
+    for j in 0..1000u32 {
+      a.send(&j.to_le_bytes()).unwrap();
+      assert_eq!(batch.recv(&b).unwrap(), 1);
+      let data = batch.iter().next().unwrap();
+      assert_eq!(data, j.to_le_bytes());
+      assert_eq!(*first.get_or_insert(data.as_ptr()), data.as_ptr());
+    }
+  }
+
+  #[test]
+  fn send_batch_flushes_everything_in_chunks() {
+    let (a, b) = UnixDatagram::pair().unwrap();
+    let mut batch = SendBatch::new(2, 0);
+    for j in 0..5u8 {
+      batch.push_with(None, |buf| buf.extend_from_slice(&[j; 4]));
+    }
+    assert!(batch.is_full());
+    assert_eq!(batch.flush(&a).unwrap(), 5);
+    assert!(batch.is_empty());
+    assert!(batch.unsent().is_empty());
+    let mut buf = [0u8; 16];
+    for j in 0..5u8 {
+      let sz = b.recv(&mut buf).unwrap();
+      assert_eq!(&buf[..sz], &[j; 4]);
+    }
+
+    // Buffers come back empty, but keep their capacity.
+    batch.push_with(Some(0), |buf| {
+      assert!(buf.is_empty() && buf.capacity() >= 4);
+      buf.push(9);
+    });
+    assert_eq!(batch.queued().collect::<Vec<_>>(), [(Some(0), 1)]);
+  }
+
+  #[test]
+  fn send_batch_keeps_backlog_while_full() {
+    let (a, b) = UnixDatagram::pair().unwrap();
+    a.set_nonblocking(true).unwrap();
+    let mut batch = SendBatch::new(4, 3);
+    let mut sent = 0;
+    let mut j = 0usize;
+    // Fill the socket until it pushes back.
+    loop {
+      batch.push_with(Some(j), |buf| buf.extend_from_slice(&j.to_le_bytes()));
+      j += 1;
+      match batch.flush(&a) {
+        Ok(n) => sent += n,
+        Err((n, e)) => {
+          assert_eq!(e, Errno::EAGAIN);
+          sent += n;
+          break;
+        }
+      }
+    }
+    assert_eq!(batch.queued().count(), 1);
+    // More than the backlog, the newest ones are dropped.
+    for _ in 0..4 {
+      batch.push_with(Some(j), |buf| buf.extend_from_slice(&j.to_le_bytes()));
+      j += 1;
+    }
+    let (n, _) = batch.flush(&a).unwrap_err();
+    assert_eq!(n, 0);
+    assert_eq!(batch.queued().count(), 3);
+    assert_eq!(batch.unsent(), [Some(j - 2), Some(j - 1)]);
+
+    // Once there is room again, the backlog goes out in order.
+    let mut buf = [0u8; 8];
+    for k in 0..sent {
+      b.recv(&mut buf).unwrap();
+      assert_eq!(usize::from_le_bytes(buf), k);
+    }
+    assert_eq!(batch.flush(&a).unwrap(), 3);
+    assert!(batch.is_empty());
+    for k in sent..sent + 3 {
+      b.recv(&mut buf).unwrap();
+      assert_eq!(usize::from_le_bytes(buf), k);
+    }
+  }
+}
@@ -0,0 +1,76 @@
+use std::time::Duration;
+
+use crate::TunnelInserterConfig;
+
+/// Bytes the kernel charges against a socket buffer per datagram on top of
+/// its payload (socket buffer bookkeeping).  Roughly what Linux accounts for
+/// a small `sk_buff`.
+const PER_PACKET_OVERHEAD: usize = 512;
+
+/// How long the forwarding loop is assumed to be kept from draining its
+/// sockets, e.g. by scheduling, before it runs again.  The buffers have to
+/// absorb everything arriving in that time.
+const ASSUMED_STALL: Duration = Duration::from_millis(10);
+
+/// `recvmmsg` calls per second the loop is assumed to manage.
+const ASSUMED_RECV_CALLS_PER_SEC: u64 = 200_000;
+
+/// Rough sustainable rate of one socket, see [`estimate_capacity`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CapacityEstimate {
+  /// Packets of `mtu` bytes a socket buffer holds.
+  pub packets_buffered: usize,
+  /// Packets per second before drops are expected.
+  pub packets_per_sec: u64,
+  /// The same in payload bytes per second.
+  pub bytes_per_sec: u64,
+}
+
+/// Estimate how many packets of `mtu` bytes per second a socket of `cfg`
+/// sustains before the kernel drops.  The rate is bounded by the socket buffer
+/// having to ride out a stall of the loop, and by how many datagrams a single
+/// `recvmmsg` call picks up.  This is meant for sizing, not a guarantee.
+pub fn estimate_capacity(cfg: &TunnelInserterConfig, mtu: usize) -> CapacityEstimate {
+  let rcvbuf = cfg.rcvbuf.unwrap_or(cfg.socket_buffer);
+  let packets_buffered = rcvbuf / (mtu + PER_PACKET_OVERHEAD);
+  let buffer_bound = (packets_buffered as f64 / ASSUMED_STALL.as_secs_f64()) as u64;
+  let batch_bound = cfg.recv_batch as u64 * ASSUMED_RECV_CALLS_PER_SEC;
+  let packets_per_sec = buffer_bound.min(batch_bound);
+  CapacityEstimate {
+    packets_buffered,
+    packets_per_sec,
+    bytes_per_sec: packets_per_sec * mtu as u64,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::estimate_capacity;
+  use crate::tests::test_config;
+
+  #[test]
+  fn estimate_scales_with_buffer_and_mtu() {
+    let mut cfg = test_config(10, 11, &[]);
+    let base = estimate_capacity(&cfg, 1500);
+    assert!(base.packets_per_sec > 0);
+    assert_eq!(base.bytes_per_sec, base.packets_per_sec * 1500);
+
+    // Larger packets fill the buffer sooner.
+    assert!(estimate_capacity(&cfg, 9000).packets_per_sec < base.packets_per_sec);
+
+    cfg.socket_buffer *= 2;
+    let doubled = estimate_capacity(&cfg, 1500);
+    assert_eq!(doubled.packets_buffered, 2 * base.packets_buffered);
+    assert!(doubled.packets_per_sec > base.packets_per_sec);
+
+    // Only the receive side buffers inbound packets.
+    cfg.sndbuf = Some(1);
+    assert_eq!(estimate_capacity(&cfg, 1500), doubled);
+    cfg.rcvbuf = Some(cfg.socket_buffer / 2);
+    assert_eq!(estimate_capacity(&cfg, 1500).packets_buffered, base.packets_buffered);
+
+    // A batch of one caps the rate at one packet per recvmmsg call.
+    cfg.recv_batch = 1;
+    assert_eq!(estimate_capacity(&cfg, 64).packets_per_sec, 200_000);
+  }
+}
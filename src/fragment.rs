@@ -0,0 +1,407 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use log::debug;
+
+use crate::udp::checksum;
+
+const IPV4_HEADER_LEN: usize = 20;
+
+/// Longest IPv4 datagram, header included.
+const MAX_DATAGRAM: usize = 65535;
+
+/// Charged against [`ReassemblyLimits::max_bytes`] for each datagram held,
+/// for its table entry.
+const DATAGRAM_COST: usize = 64;
+
+/// Charged for each fragment held, for its range.
+const FRAGMENT_COST: usize = 16;
+
+/// Bounds on the fragments held by a [`Reassembler`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ReassemblyLimits {
+  /// Drop a datagram whose fragments did not all arrive within this time of
+  /// the first one.
+  pub timeout: Duration,
+  /// Most bytes held at once, over all datagrams: fragment payloads and
+  /// headers, plus a fixed cost per datagram and per fragment.  Further
+  /// fragments are dropped until some datagram completes or times out.
+  pub max_bytes: usize,
+}
+
+impl Default for ReassemblyLimits {
+  fn default() -> Self {
+    Self {
+      timeout: Duration::from_secs(2),
+      max_bytes: 1 << 20,
+    }
+  }
+}
+
+/// Fragments belonging to the same datagram share these (RFC 791).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+struct FragmentKey {
+  src: [u8; 4],
+  dst: [u8; 4],
+  id: u16,
+  protocol: u8,
+}
+
+/// The fragments of one datagram received so far.
+#[derive(Debug)]
+struct PartialDatagram {
+  first_seen: Instant,
+  /// Header of the fragment at offset 0, once it arrived.
+  header: Option<Vec<u8>>,
+  /// Payload bytes at their offsets, zero where nothing arrived yet.
+  data: Vec<u8>,
+  /// Payload ranges received, as `(start, end)`.
+  ranges: Vec<(usize, usize)>,
+  /// Payload length, known once the last fragment arrived.
+  len: Option<usize>,
+}
+
+impl PartialDatagram {
+  /// Bytes charged against the limit for this datagram.
+  fn size(&self) -> usize {
+    let header = self.header.as_ref().map_or(0, Vec::len);
+    DATAGRAM_COST + header + self.data.len() + self.ranges.len() * FRAGMENT_COST
+  }
+
+  fn is_complete(&mut self) -> bool {
+    let (Some(len), Some(_)) = (self.len, &self.header) else {
+      return false;
+    };
+    self.ranges.sort_unstable();
+    let mut covered = 0;
+    for &(start, end) in &self.ranges {
+      if start > covered {
+        return false;
+      }
+      covered = covered.max(end);
+    }
+    covered == len
+  }
+}
+
+/// What became of a fragment handed to [`Reassembler::push`].
+#[derive(Debug, PartialEq, Eq)]
+pub enum Reassembly {
+  /// Held until the rest of its datagram arrives.
+  Incomplete,
+  /// The fragment completed its datagram, returned as one unfragmented
+  /// IPv4 packet.
+  Complete(Vec<u8>),
+  /// Malformed, overlapping another fragment, too long once reassembled, or
+  /// over the byte limit.
+  Dropped,
+}
+
+/// Whether `packet`, an IPv4 packet, is a fragment: more fragments follow
+/// or it does not start at offset 0.
+pub fn is_fragment(packet: &[u8]) -> bool {
+  packet.len() >= IPV4_HEADER_LEN && u16::from_be_bytes([packet[6], packet[7]]) & 0x3fff != 0
+}
+
+/// Puts fragmented IPv4 datagrams back together, so that the UDP header,
+/// which only the first fragment carries, can be checked over the whole
+/// datagram.
+#[derive(Debug)]
+pub struct Reassembler {
+  limits: ReassemblyLimits,
+  pending: HashMap<FragmentKey, PartialDatagram>,
+  /// Bytes charged for `pending`, see [`PartialDatagram::size`].
+  buffered: usize,
+}
+
+impl Reassembler {
+  pub fn new(limits: ReassemblyLimits) -> Self {
+    Self {
+      limits,
+      pending: HashMap::new(),
+      buffered: 0,
+    }
+  }
+
+  /// Add `packet`, an IPv4 fragment without link layer header.
+  pub fn push(&mut self, now: Instant, packet: &[u8]) -> Reassembly {
+    let Some((packet, key, ihl, offset, more)) = fragment_fields(packet) else {
+      return Reassembly::Dropped;
+    };
+    let payload = &packet[ihl..];
+    let end = offset + payload.len();
+    // Only the last fragment may be empty, more of them would cost a range
+    // each without adding anything.
+    if end + ihl > MAX_DATAGRAM || (more && (payload.is_empty() || !payload.len().is_multiple_of(8))) {
+      debug!("Bad fragment at offset {offset} of {} bytes", payload.len());
+      return Reassembly::Dropped;
+    }
+
+    let fresh = !self.pending.contains_key(&key);
+    let dgram = self.pending.entry(key).or_insert_with(|| PartialDatagram {
+      first_seen: now,
+      header: None,
+      data: Vec::new(),
+      ranges: Vec::new(),
+      len: None,
+    });
+    let overlaps = dgram.ranges.iter().any(|&(s, e)| s < end && offset < e);
+    let bad_len = match dgram.len {
+      Some(len) => end > len || !more,
+      None => !more && dgram.ranges.iter().any(|&(_, e)| e > end),
+    };
+    if overlaps || bad_len {
+      // Overlapping fragments are a classic way to sneak data past
+      // inspection, give up on the whole datagram.
+      debug!("Inconsistent fragment at offset {offset}, dropping the datagram");
+      let dgram = self.pending.remove(&key).unwrap();
+      self.buffered -= dgram.size();
+      return Reassembly::Dropped;
+    }
+    let held = if fresh { 0 } else { dgram.size() };
+    let header = if offset == 0 { ihl } else { dgram.header.as_ref().map_or(0, Vec::len) };
+    let grown = DATAGRAM_COST + header + end.max(dgram.data.len()) + (dgram.ranges.len() + 1) * FRAGMENT_COST - held;
+    if self.buffered + grown > self.limits.max_bytes {
+      debug!("Fragment buffer full, dropping a fragment");
+      if dgram.ranges.is_empty() {
+        self.pending.remove(&key);
+      }
+      return Reassembly::Dropped;
+    }
+
+    if dgram.data.len() < end {
+      dgram.data.resize(end, 0);
+    }
+    self.buffered += grown;
+    dgram.data[offset..end].copy_from_slice(payload);
+    dgram.ranges.push((offset, end));
+    if offset == 0 {
+      dgram.header = Some(packet[..ihl].to_vec());
+    }
+    if !more {
+      dgram.len = Some(end);
+    }
+    if !dgram.is_complete() {
+      return Reassembly::Incomplete;
+    }
+
+    let dgram = self.pending.remove(&key).unwrap();
+    self.buffered -= dgram.size();
+    let mut out = dgram.header.unwrap();
+    // The fragments were checked against their own header length, the first
+    // one's may be longer.
+    let Ok(total_length) = u16::try_from(out.len() + dgram.data.len()) else {
+      debug!("Reassembled datagram of {} bytes is too long", out.len() + dgram.data.len());
+      return Reassembly::Dropped;
+    };
+    out[2..4].copy_from_slice(&total_length.to_be_bytes());
+    // Keep the reserved and don't fragment bits, clear the rest.
+    out[6] &= 0xc0;
+    out[7] = 0;
+    out[10..12].fill(0);
+    let sum = checksum(&out);
+    out[10..12].copy_from_slice(&sum.to_be_bytes());
+    out.extend_from_slice(&dgram.data);
+    Reassembly::Complete(out)
+  }
+
+  /// Drop the datagrams whose time is up, returning how many.
+  pub fn expire(&mut self, now: Instant) -> usize {
+    let timeout = self.limits.timeout;
+    let before = self.pending.len();
+    let mut freed = 0;
+    self.pending.retain(|_, d| {
+      let keep = now.saturating_duration_since(d.first_seen) < timeout;
+      if !keep {
+        freed += d.size();
+      }
+      keep
+    });
+    self.buffered -= freed;
+    before - self.pending.len()
+  }
+}
+
+/// A valid IPv4 fragment cut to its total length, with its key, header
+/// length, payload offset and more fragments flag.
+fn fragment_fields(packet: &[u8]) -> Option<(&[u8], FragmentKey, usize, usize, bool)> {
+  if packet.len() < IPV4_HEADER_LEN || packet[0] >> 4 != 4 {
+    debug!("Fragment is not an IPv4 packet");
+    return None;
+  }
+  let ihl = (packet[0] & 0x0f) as usize * 4;
+  let total_length = u16::from_be_bytes([packet[2], packet[3]]) as usize;
+  if ihl < IPV4_HEADER_LEN || total_length < ihl || total_length > packet.len() {
+    debug!("Bad fragment header length {ihl} or total length {total_length}");
+    return None;
+  }
+  if checksum(&packet[..ihl]) != 0 {
+    debug!("Invalid IPv4 header checksum on a fragment");
+    return None;
+  }
+  let flags_offset = u16::from_be_bytes([packet[6], packet[7]]);
+  let key = FragmentKey {
+    src: packet[12..16].try_into().unwrap(),
+    dst: packet[16..20].try_into().unwrap(),
+    id: u16::from_be_bytes([packet[4], packet[5]]),
+    protocol: packet[9],
+  };
+  let offset = (flags_offset & 0x1fff) as usize * 8;
+  Some((&packet[..total_length], key, ihl, offset, flags_offset & 0x2000 != 0))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+  use super::{is_fragment, Reassembler, Reassembly, ReassemblyLimits};
+  use crate::udp::{checksum, create_ipv4_udp_packet, parse_ipv4_udp_packet};
+  use std::net::Ipv4Addr;
+  use std::time::{Duration, Instant};
+
+  /// Split `packet` into fragments carrying at most `chunk` payload bytes.
+  pub(crate) fn fragment(packet: &[u8], chunk: usize, id: u16) -> Vec<Vec<u8>> {
+    let (header, payload) = packet.split_at(20);
+    let chunks: Vec<&[u8]> = payload.chunks(chunk).collect();
+    chunks
+      .iter()
+      .enumerate()
+      .map(|(i, data)| {
+        let mut frag = header.to_vec();
+        let more = if i + 1 < chunks.len() { 0x2000 } else { 0 };
+        let flags_offset = more | (i * chunk / 8) as u16;
+        frag[2..4].copy_from_slice(&((20 + data.len()) as u16).to_be_bytes());
+        frag[4..6].copy_from_slice(&id.to_be_bytes());
+        frag[6..8].copy_from_slice(&flags_offset.to_be_bytes());
+        frag[10..12].fill(0);
+        let sum = checksum(&frag);
+        frag[10..12].copy_from_slice(&sum.to_be_bytes());
+        frag.extend_from_slice(data);
+        frag
+      })
+      .collect()
+  }
+
+  /// A fragment with `options` bytes of IP options.
+  fn raw_fragment(options: usize, offset: usize, more: bool, id: u16, payload: &[u8]) -> Vec<u8> {
+    let ihl = 20 + options;
+    let mut frag = vec![0u8; ihl];
+    frag[0] = 0x40 | (ihl / 4) as u8;
+    frag[2..4].copy_from_slice(&((ihl + payload.len()) as u16).to_be_bytes());
+    frag[4..6].copy_from_slice(&id.to_be_bytes());
+    let flags_offset = if more { 0x2000 } else { 0 } | (offset / 8) as u16;
+    frag[6..8].copy_from_slice(&flags_offset.to_be_bytes());
+    frag[8] = 64;
+    frag[9] = 17;
+    frag[12..16].copy_from_slice(&[10, 0, 0, 2]);
+    frag[16..20].copy_from_slice(&[10, 0, 0, 1]);
+    let sum = checksum(&frag);
+    frag[10..12].copy_from_slice(&sum.to_be_bytes());
+    frag.extend_from_slice(payload);
+    frag
+  }
+
+  fn udp_packet(payload: &[u8]) -> Vec<u8> {
+    let src = Ipv4Addr::new(10, 0, 0, 2);
+    let dst = Ipv4Addr::new(10, 0, 0, 1);
+    create_ipv4_udp_packet(payload, src, dst, 3000, 2000)
+  }
+
+  #[test]
+  fn reassembles_two_fragments() {
+    let payload: Vec<u8> = (0..100).collect();
+    let packet = udp_packet(&payload);
+    let frags = fragment(&packet, 64, 7);
+    assert_eq!(frags.len(), 2);
+    assert!(frags.iter().all(|f| is_fragment(f)));
+    assert!(!is_fragment(&packet));
+    // The second fragment has no UDP header of its own.
+    assert!(parse_ipv4_udp_packet(&frags[1]).is_err());
+
+    // In either order.
+    for order in [[0, 1], [1, 0]] {
+      let mut r = Reassembler::new(ReassemblyLimits::default());
+      let now = Instant::now();
+      assert_eq!(r.push(now, &frags[order[0]]), Reassembly::Incomplete);
+      let Reassembly::Complete(whole) = r.push(now, &frags[order[1]]) else {
+        panic!("not reassembled");
+      };
+      assert!(!is_fragment(&whole));
+      assert_eq!(parse_ipv4_udp_packet(&whole).unwrap().payload, &payload[..]);
+      assert_eq!(r.pending.len(), 0);
+    }
+  }
+
+  #[test]
+  fn drops_overlapping_fragments() {
+    let packet = udp_packet(&[1; 100]);
+    let mut r = Reassembler::new(ReassemblyLimits::default());
+    let now = Instant::now();
+    let frags = fragment(&packet, 64, 1);
+    assert_eq!(r.push(now, &frags[0]), Reassembly::Incomplete);
+    assert_eq!(r.push(now, &frags[0]), Reassembly::Dropped);
+    assert_eq!(r.pending.len(), 0);
+  }
+
+  #[test]
+  fn evicts_incomplete_datagrams() {
+    let limits = ReassemblyLimits {
+      timeout: Duration::from_secs(1),
+      max_bytes: 300,
+    };
+    let mut r = Reassembler::new(limits);
+    let t0 = Instant::now();
+    let big = udp_packet(&[2; 200]);
+    let frags = fragment(&big, 64, 1);
+    assert_eq!(r.push(t0, &frags[0]), Reassembly::Incomplete);
+    assert_eq!(r.push(t0, &frags[1]), Reassembly::Incomplete);
+    // Over the byte limit.
+    assert_eq!(r.push(t0, &frags[2]), Reassembly::Dropped);
+    assert_eq!(r.expire(t0 + Duration::from_millis(500)), 0);
+    assert_eq!(r.expire(t0 + Duration::from_secs(1)), 1);
+    assert_eq!(r.pending.len(), 0);
+
+    // The space is free again.
+    let small = fragment(&udp_packet(&[3; 100]), 64, 2);
+    assert_eq!(r.push(t0, &small[0]), Reassembly::Incomplete);
+    assert!(matches!(r.push(t0, &small[1]), Reassembly::Complete(_)));
+  }
+
+  #[test]
+  fn charges_headers_and_entries() {
+    let limits = ReassemblyLimits {
+      timeout: Duration::from_secs(1),
+      max_bytes: 1000,
+    };
+    let mut r = Reassembler::new(limits);
+    let now = Instant::now();
+    // Tiny fragments of many datagrams cost more than their payload.
+    let held = (0..100).filter(|&id| r.push(now, &raw_fragment(0, 8, true, id, &[0; 8])) == Reassembly::Incomplete);
+    assert_eq!(held.count(), 1000 / (64 + 16 + 16));
+    assert!(r.buffered <= 1000);
+    assert_eq!(r.expire(now + Duration::from_secs(1)), 10);
+    assert_eq!(r.buffered, 0);
+
+    // Only the last fragment may be empty.
+    assert_eq!(r.push(now, &raw_fragment(0, 8, true, 1, &[])), Reassembly::Dropped);
+    assert_eq!(r.push(now, &raw_fragment(0, 8, false, 1, &[])), Reassembly::Incomplete);
+    assert_eq!(r.pending.len(), 1);
+  }
+
+  #[test]
+  fn drops_datagrams_too_long_with_the_first_header() {
+    let mut r = Reassembler::new(ReassemblyLimits::default());
+    let now = Instant::now();
+    // 20 bytes of header leave room for this much payload, 60 don't.
+    let len = 65535 - 20 - 7;
+    let payload = vec![0u8; len];
+    let (head, tail) = payload.split_at(65000);
+    assert_eq!(r.push(now, &raw_fragment(0, 65000, false, 1, tail)), Reassembly::Incomplete);
+    assert_eq!(r.push(now, &raw_fragment(40, 0, true, 1, &head[..1000])), Reassembly::Incomplete);
+    let mut last = Reassembly::Incomplete;
+    for offset in (1000..65000).step_by(8000) {
+      let end = (offset + 8000).min(65000);
+      last = r.push(now, &raw_fragment(0, offset, true, 1, &head[offset..end]));
+    }
+    assert_eq!(last, Reassembly::Dropped);
+    assert_eq!((r.pending.len(), r.buffered), (0, 0));
+  }
+}
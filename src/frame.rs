@@ -0,0 +1,279 @@
+use std::time::{Duration, Instant};
+
+/*
+A frame packs one or more local datagrams into a single tunnel payload:
+
+  offset  size  content
+  0       1     version, 1 or 2
+  1       1     number of records n
+  2       4     version 2 only: sequence number, u32 big endian
+  ...     ...   n records, each a u16 big endian length followed by the data
+
+Bytes following the last record are padding and ignored by the receiver.  The
+sender may zero pad small frames so that packet sizes say less about the
+datagrams inside.
+
+Version 2 frames carry a per port pair sequence number, counting frames, which
+lets the receiver measure loss without looking into the datagrams.
+*/
+const FRAME_VERSION: u8 = 1;
+const FRAME_VERSION_SEQ: u8 = 2;
+const FRAME_HEADER_LEN: usize = 2;
+const SEQ_LEN: usize = 4;
+const RECORD_HEADER_LEN: usize = 2;
+const MAX_RECORDS: u8 = u8::MAX;
+
+/// Limits for coalescing outbound datagrams of one port pair into a frame.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize))]
+pub struct Coalesce {
+  /// A frame is sent as soon as adding another datagram would exceed this
+  /// many bytes.
+  pub max_bytes: usize,
+  /// A frame is sent at the latest this long after its first datagram was
+  /// added.
+  pub max_delay: Duration,
+}
+
+/// Accumulates datagrams into a frame.
+#[derive(Debug)]
+pub struct FrameBuilder {
+  buf: Vec<u8>,
+  first: Option<Instant>,
+  /// Sequence number of this frame, for version 2 frames.
+  seq: Option<u32>,
+}
+
+impl Default for FrameBuilder {
+  fn default() -> Self {
+    Self {
+      buf: vec![FRAME_VERSION, 0],
+      first: None,
+      seq: None,
+    }
+  }
+}
+
+impl FrameBuilder {
+  /// A builder for version 2 frames, numbered from `seq` on.
+  pub fn with_seq(seq: u32) -> Self {
+    let mut buf = vec![FRAME_VERSION_SEQ, 0];
+    buf.extend_from_slice(&seq.to_be_bytes());
+    Self {
+      buf,
+      first: None,
+      seq: Some(seq),
+    }
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.buf[1] == 0
+  }
+
+  /// Whether a datagram of `len` bytes can be added without the frame
+  /// exceeding `max_bytes`.
+  pub fn fits(&self, len: usize, max_bytes: usize) -> bool {
+    self.buf[1] < MAX_RECORDS && self.buf.len() + RECORD_HEADER_LEN + len <= max_bytes
+  }
+
+  pub fn push(&mut self, data: &[u8], now: Instant) {
+    let len = u16::try_from(data.len()).expect("datagram too long for a frame record");
+    self.buf.extend_from_slice(&len.to_be_bytes());
+    self.buf.extend_from_slice(data);
+    self.buf[1] += 1;
+    self.first.get_or_insert(now);
+  }
+
+  /// When the frame has to be sent at the latest, if it holds anything.
+  pub fn deadline(&self, max_delay: Duration) -> Option<Instant> {
+    self.first.map(|t| t + max_delay)
+  }
+
+  /// Return the finished frame and start a new, empty one, numbered one
+  /// higher if frames are numbered.
+  pub fn take(&mut self) -> Vec<u8> {
+    let next = match self.seq {
+      Some(seq) => Self::with_seq(seq.wrapping_add(1)),
+      None => Self::default(),
+    };
+    std::mem::replace(self, next).buf
+  }
+
+  /// Like [`FrameBuilder::take`], but zero pad the frame to at least
+  /// `min_len` bytes.
+  pub fn take_padded(&mut self, min_len: usize) -> Vec<u8> {
+    let mut frame = self.take();
+    if frame.len() < min_len {
+      frame.resize(min_len, 0);
+    }
+    frame
+  }
+}
+
+/// Split a frame into its records.  Returns `None` if the frame is malformed.
+pub fn parse_frame(frame: &[u8]) -> Option<Vec<&[u8]>> {
+  let header_len = match *frame.first()? {
+    FRAME_VERSION => FRAME_HEADER_LEN,
+    FRAME_VERSION_SEQ => FRAME_HEADER_LEN + SEQ_LEN,
+    _ => return None,
+  };
+  let mut rest = frame.get(header_len..)?;
+  let mut records = Vec::with_capacity(usize::from(frame[1]));
+  for _ in 0..frame[1] {
+    if rest.len() < RECORD_HEADER_LEN {
+      return None;
+    }
+    let len = usize::from(u16::from_be_bytes([rest[0], rest[1]]));
+    let end = RECORD_HEADER_LEN + len;
+    records.push(rest.get(RECORD_HEADER_LEN..end)?);
+    rest = &rest[end..];
+  }
+  Some(records)
+}
+
+/// Sequence number of a version 2 frame.
+pub fn frame_seq(frame: &[u8]) -> Option<u32> {
+  match frame {
+    [FRAME_VERSION_SEQ, _, a, b, c, d, ..] => Some(u32::from_be_bytes([*a, *b, *c, *d])),
+    _ => None,
+  }
+}
+
+/// Detects missing frame sequence numbers of one port pair, tolerating
+/// reordering: a number only counts as lost once `window` higher numbers
+/// have been seen without it showing up.
+#[derive(Debug, Default)]
+pub struct LossTracker {
+  highest: Option<u32>,
+  /// Bit `i` is set if `highest - i` was received.
+  seen: u64,
+}
+
+impl LossTracker {
+  /// Largest supported reordering window.
+  pub const MAX_WINDOW: u32 = 64;
+
+  /// Record the arrival of `seq` and return how many sequence numbers were
+  /// newly declared lost.  `window` must be in `1..=MAX_WINDOW`.
+  ///
+  /// Returns `None` for a duplicate, or a frame so late that it was already
+  /// declared lost.  Such a frame doesn't count as received either: losses
+  /// are never taken back, so the counters only go up and the loss rate
+  /// stays exact.
+  pub fn record(&mut self, seq: u32, window: u32) -> Option<u64> {
+    let w = u64::from(window);
+    let mask = if w == 64 { u64::MAX } else { (1 << w) - 1 };
+    let Some(highest) = self.highest else {
+      // Nothing before the first frame counts as missing.
+      self.highest = Some(seq);
+      self.seen = mask;
+      return Some(0);
+    };
+    // Signed distance, so that wrap-around of the counter is harmless.
+    let ahead = seq.wrapping_sub(highest) as i32;
+    if ahead <= 0 {
+      // Late or duplicate.  Older than the window means already counted.
+      let back = u64::from(ahead.unsigned_abs());
+      if back >= w || self.seen & (1 << back) != 0 {
+        return None;
+      }
+      self.seen |= 1 << back;
+      return Some(0);
+    }
+    let shift = u64::from(ahead.unsigned_abs());
+    self.highest = Some(seq);
+    let lost = if shift >= w {
+      // The whole window moves out, plus what was skipped beyond it.
+      w - u64::from((self.seen & mask).count_ones()) + (shift - w)
+    } else {
+      let leaving = mask & !(mask >> shift);
+      shift - u64::from((self.seen & leaving).count_ones())
+    };
+    self.seen = if shift >= 64 { 1 } else { ((self.seen << shift) | 1) & mask };
+    Some(lost)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{frame_seq, parse_frame, FrameBuilder, LossTracker};
+  use std::time::{Duration, Instant};
+
+  fn single_frame(data: &[u8]) -> Vec<u8> {
+    let mut fb = FrameBuilder::default();
+    fb.push(data, Instant::now());
+    fb.take()
+  }
+
+  #[test]
+  fn frame_round_trip() {
+    let now = Instant::now();
+    let mut fb = FrameBuilder::default();
+    assert!(fb.is_empty());
+    assert_eq!(fb.deadline(Duration::from_millis(1)), None);
+    for data in [&b"one"[..], b"", b"three"] {
+      assert!(fb.fits(data.len(), 64));
+      fb.push(data, now);
+    }
+    assert!(!fb.fits(50, 64));
+    assert_eq!(fb.deadline(Duration::from_millis(1)), Some(now + Duration::from_millis(1)));
+    let frame = fb.take();
+    assert!(fb.is_empty());
+    assert_eq!(parse_frame(&frame).unwrap(), vec![&b"one"[..], b"", b"three"]);
+    assert_eq!(parse_frame(&single_frame(b"x")).unwrap(), vec![&b"x"[..]]);
+  }
+
+  #[test]
+  fn malformed_frames() {
+    let frame = single_frame(b"data");
+    assert!(parse_frame(&frame[..frame.len() - 1]).is_none());
+    assert!(parse_frame(&[]).is_none());
+    assert!(parse_frame(&[2, 0]).is_none());
+    // Trailing bytes after the last record are padding.
+    let mut padded = frame.clone();
+    padded.extend_from_slice(&[0; 10]);
+    assert_eq!(parse_frame(&padded).unwrap(), vec![&b"data"[..]]);
+  }
+
+  #[test]
+  fn padded_frames() {
+    let mut fb = FrameBuilder::default();
+    fb.push(b"abc", Instant::now());
+    let frame = fb.take_padded(64);
+    assert_eq!(frame.len(), 64);
+    assert_eq!(parse_frame(&frame).unwrap(), vec![&b"abc"[..]]);
+
+    // Frames already at the minimum are left alone.
+    fb.push(&[1; 100], Instant::now());
+    assert_eq!(fb.take_padded(64).len(), 2 + 2 + 100);
+  }
+
+  #[test]
+  fn numbered_frames() {
+    let mut fb = FrameBuilder::with_seq(u32::MAX);
+    for expected in [u32::MAX, 0] {
+      fb.push(b"seq", Instant::now());
+      let frame = fb.take();
+      assert_eq!(frame_seq(&frame), Some(expected));
+      assert_eq!(parse_frame(&frame).unwrap(), vec![&b"seq"[..]]);
+    }
+    assert_eq!(frame_seq(&single_frame(b"v1")), None);
+  }
+
+  #[test]
+  fn loss_tracker_tolerates_reordering() {
+    let mut t = LossTracker::default();
+    // 2 and 3 arrive late but within the window of 4, 5 never arrives.
+    let lost: u64 = [0, 1, 4, 2, 3, 6, 7, 8, 9, 10].iter().map(|&s| t.record(s, 4).unwrap()).sum();
+    assert_eq!(lost, 1);
+    // A jump beyond the window loses what was skipped, except for the
+    // numbers still inside the window.
+    assert_eq!(t.record(30, 4), Some(16));
+    // Too late to matter, already counted as lost.
+    assert_eq!(t.record(5, 4), None);
+    // Duplicates count once.
+    assert_eq!(t.record(30, 4), None);
+    assert_eq!(t.record(28, 4), Some(0));
+    assert_eq!(t.record(28, 4), None);
+  }
+}
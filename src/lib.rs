@@ -1,35 +1,776 @@
+use std::any::Any;
 use std::collections::HashMap;
 use std::fs::File;
-use std::net::Ipv4Addr;
-use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::ops::RangeInclusive;
+use std::os::fd::{AsFd, AsRawFd, FromRawFd, OwnedFd, RawFd};
 use std::os::unix::net::UnixDatagram;
+use std::path::PathBuf;
+use std::process::Child;
+use std::sync::atomic::AtomicBool;
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
 
-use nix::sys::socket::{setsockopt, sockopt};
+use log::{info, warn};
+use nix::errno::Errno;
+use nix::sys::socket::sockopt;
 
 use axl::{axl_tunnel_app, TunnelArgs};
 use clap::{Arg, ArgAction, Command};
 
+#[cfg(feature = "tokio")]
+mod async_forward;
+mod batch;
+mod capacity;
 mod forward;
+mod fragment;
+mod frame;
+mod metrics;
+mod pcap;
+mod process;
+mod sched;
 mod sock_utils;
+mod stats;
+mod syslog;
 mod udp;
 
-use crate::forward::{forward, PortPair};
-use crate::sock_utils::set_cloexec;
+use crate::forward::{describe_pair, forward, DEFAULT_MAX_DATAGRAM};
+use crate::frame::LossTracker;
+use crate::pcap::PcapWriter;
+
+pub use crate::forward::{
+  Delivery, FlowIdDemux, ForwardEngine, ForwardOptions, IpIdMode, OutsideMode, OutsideSocket, PortPair,
+  RateLimit, SpoofAction, SpoofGuard, TraceEvent, TraceHook,
+};
+use crate::sched::set_current_thread;
+use crate::sock_utils::{bind_to_device, connect_unix_peer, probe_socket_pair, set_buffer_size, set_cloexec};
+use crate::udp::MAX_HEADERS_LEN;
+
+#[cfg(feature = "tokio")]
+pub use crate::async_forward::forward_async;
+pub use crate::capacity::{estimate_capacity, CapacityEstimate};
+pub use crate::fragment::ReassemblyLimits;
+pub use crate::frame::Coalesce;
+pub use crate::metrics::{render_prometheus, MetricsServer};
+pub use crate::process::{spawn_process, stop_process};
+pub use crate::sched::{RtSched, SchedPolicy};
+pub use crate::stats::{ForwardStats, PairSnapshot, PairStats, StatsRegistry, StatsSnapshot};
+pub use crate::syslog::{facility_code, SyslogLogger, SyslogTarget};
+pub use crate::udp::{checksum_update, patch_udp_payload, self_test, ChecksumMode, LinkLayer, ParseError, ParseOptions};
 
 /// Configuration for [`TunnelInserter`].
+///
+/// With the `serde` feature the config can also be read from JSON, see
+/// [`TunnelInserterConfig::from_json`].  Every field but the descriptors, the
+/// addresses and `axlrust_args` may be left out and takes its default.
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize), serde(deny_unknown_fields))]
 pub struct TunnelInserterConfig {
   pub outside_fd: i32,
   pub control_fd: i32,
-  pub local_addr: Ipv4Addr,
-  pub remote_addr: Ipv4Addr,
+  /// Our address on the outside, IPv4 or IPv6.  The unspecified address
+  /// (`0.0.0.0` or `::`) accepts inbound packets to any destination address,
+  /// e.g. when the outside sees the server's public address.  It is still
+  /// the source address of the packets sent to the outside.
+  pub local_addr: IpAddr,
+  /// The peer's address, of the same family as `local_addr`.
+  pub remote_addr: IpAddr,
+  /// Peer address for each port pair, overriding `remote_addr`, for tunnels
+  /// to several peers.  Either empty or one per port pair.
+  #[cfg_attr(feature = "serde", serde(default))]
+  pub remote_addrs: Vec<IpAddr>,
+  #[cfg_attr(feature = "serde", serde(default))]
   pub local_ports: Vec<u16>,
+  #[cfg_attr(feature = "serde", serde(default))]
   pub remote_ports: Vec<u16>,
+  /// File for the stderr of AxlRust, started with its arguments.  Only an
+  /// AxlRust process (`axlrust_exec`) can have its stderr redirected; a
+  /// tunnel thread shares the stderr of this process, so the file just gets
+  /// the invocation.  Also where a panic of the forwarding loop is dumped.
+  #[cfg_attr(feature = "serde", serde(default))]
   pub stderr_file: Option<String>,
+  /// Maximum number of datagrams gathered per `recvmmsg` call.  Small values
+  /// favour latency, large values throughput.
+  #[cfg_attr(feature = "serde", serde(default = "default_batch"))]
+  pub recv_batch: usize,
+  /// Maximum number of packets sent to the outside per `sendmmsg` call.
+  #[cfg_attr(feature = "serde", serde(default = "default_batch"))]
+  pub send_batch: usize,
+  /// Packets held back while the outside socket is full, sent once it takes
+  /// packets again.  Beyond that they are dropped.  0 drops right away.
+  #[cfg_attr(feature = "serde", serde(default = "default_send_backlog"))]
+  pub send_backlog: usize,
+  /// Send local datagrams to the outside one by one with a gather write of
+  /// headers and payload, rather than copied into packets for `sendmmsg`.
+  #[cfg_attr(feature = "serde", serde(default))]
+  pub zero_copy: bool,
+  /// Receive buffer size in bytes.  Larger datagrams are dropped and counted
+  /// in `oversize_drops`; raise this for jumbo frames.
+  #[cfg_attr(feature = "serde", serde(default = "default_max_datagram"))]
+  pub max_datagram: usize,
+  /// Echo every valid inbound packet back to the outside (with source and
+  /// destination swapped) instead of delivering it locally.  For bringup.
+  #[cfg_attr(feature = "serde", serde(default))]
+  pub echo: bool,
+  /// Probe every created socket pair before forwarding starts and fail if one
+  /// of them does not pass a datagram in both directions.
+  #[cfg_attr(feature = "serde", serde(default))]
+  pub self_check: bool,
+  /// Coalesce small outbound datagrams of a port pair into one tunnel packet.
+  /// The far end must run with coalescing as well to split them again.
+  #[cfg_attr(feature = "serde", serde(default))]
+  pub coalesce: Option<Coalesce>,
+  /// Stop forwarding after this many consecutive `epoll_wait` wakeups without any
+  /// data, rather than spinning at full CPU.
+  #[cfg_attr(feature = "serde", serde(default))]
+  pub max_spins: Option<usize>,
   /// Arguments for the AxlRust component.  Place holders like `{fd0}` will be
   /// substituted with the file descriptors of the sockets created by the
   /// inserter.
   pub axlrust_args: Vec<String>,
+  /// Axl config items, passed as `-x key=value` after `axlrust_args`.  Place
+  /// holders in the values are substituted as well.
+  #[cfg_attr(feature = "serde", serde(default))]
+  pub axl_config_items: Vec<(String, String)>,
+  /// Accept 0, 1 or 2 as `outside_fd`/`control_fd`.  Off by default because
+  /// it usually means a wrong command line rather than intent.
+  #[cfg_attr(feature = "serde", serde(default))]
+  pub allow_stdio_fds: bool,
+  /// How the IPv4 identification field of encapsulated packets is chosen.
+  #[cfg_attr(feature = "serde", serde(default))]
+  pub ip_id: IpIdMode,
+  /// Drop inbound packets with the reserved IPv4 flag bit set instead of
+  /// ignoring the bit.
+  #[cfg_attr(feature = "serde", serde(default))]
+  pub reject_reserved_flag: bool,
+  /// Demux inbound packets by an embedded flow id instead of by port pair.
+  /// The table maps flow ids to local socket indices, i.e. the `N` of
+  /// `{fdN}`.
+  #[cfg_attr(feature = "serde", serde(default))]
+  pub flow_demux: Option<FlowIdDemux>,
+  /// Port pairs behind a NAT that changes the ports of inbound packets, each
+  /// with the ports its inbound packets arrive with: destination port as
+  /// `local`, source port as `remote`.  See [`parse_return_ports`].
+  #[cfg_attr(feature = "serde", serde(default))]
+  pub return_ports: Vec<(PortPair, PortPair)>,
+  /// Real-time scheduling for the forwarding loop, which runs on the thread
+  /// calling [`TunnelInserter::run`].  See [`RtSched`] for the risks.
+  #[cfg_attr(feature = "serde", serde(default))]
+  pub rt_sched: Option<RtSched>,
+  /// Drop inbound packets with an empty payload rather than delivering an
+  /// empty datagram.
+  #[cfg_attr(feature = "serde", serde(default))]
+  pub drop_empty: bool,
+  /// Optional operator-facing names for the port pairs, e.g. "voice", used
+  /// in log messages and metrics.  Either empty or one per port pair.
+  #[cfg_attr(feature = "serde", serde(default))]
+  pub pair_names: Vec<String>,
+  /// Zero pad coalesced frames to at least this many bytes.  Requires
+  /// `coalesce`.
+  #[cfg_attr(feature = "serde", serde(default))]
+  pub pad_to: Option<usize>,
+  /// Stop forwarding after this long, as if the control pipe had been
+  /// closed.
+  #[cfg_attr(feature = "serde", serde(default))]
+  pub max_runtime: Option<Duration>,
+  /// Log when a port pair has seen no packet in either direction for this
+  /// long, e.g. to find AxlRust tunnels nobody uses any more.
+  #[cfg_attr(feature = "serde", serde(default))]
+  pub idle_timeout: Option<Duration>,
+  /// Link layer header in front of the IPv4 header of inbound packets.
+  #[cfg_attr(feature = "serde", serde(default))]
+  pub link_layer: LinkLayer,
+  /// Permitted UDP source ports of inbound packets, checked before demux.
+  #[cfg_attr(feature = "serde", serde(default))]
+  pub allowed_src_ports: Option<RangeInclusive<u16>>,
+  /// Permitted UDP destination ports of inbound packets.
+  #[cfg_attr(feature = "serde", serde(default))]
+  pub allowed_dst_ports: Option<RangeInclusive<u16>>,
+  /// Warn, or tighten inbound validation for a while, when too many packets
+  /// arrive from the wrong source IP within a window.
+  #[cfg_attr(feature = "serde", serde(default))]
+  pub spoof_guard: Option<SpoofGuard>,
+  /// Drop datagrams from AxlRust beyond this rate, per port pair, to spare
+  /// a fragile network downstream.
+  #[cfg_attr(feature = "serde", serde(default))]
+  pub rate_limit: Option<RateLimit>,
+  /// `SO_RCVBUF` and `SO_SNDBUF` of the sockets passed to AxlRust, in bytes.
+  #[cfg_attr(feature = "serde", serde(default = "default_socket_buffer"))]
+  pub socket_buffer: usize,
+  /// `SO_RCVBUF` only, overriding `socket_buffer`.
+  #[cfg_attr(feature = "serde", serde(default))]
+  pub rcvbuf: Option<usize>,
+  /// `SO_SNDBUF` only, overriding `socket_buffer`.
+  #[cfg_attr(feature = "serde", serde(default))]
+  pub sndbuf: Option<usize>,
+  /// Number outbound frames and count gaps in inbound frame numbers as lost,
+  /// tolerating this much reordering.  Requires `coalesce`, and the far end
+  /// needs to number its frames too.
+  #[cfg_attr(feature = "serde", serde(default))]
+  pub seq_window: Option<u32>,
+  /// Drop inbound packets whose outer TTL is below this.
+  #[cfg_attr(feature = "serde", serde(default))]
+  pub min_ttl: Option<u8>,
+  /// Run `axlrust_args` as an external program which inherits the sockets,
+  /// instead of the tunnel app in a thread.
+  #[cfg_attr(feature = "serde", serde(default))]
+  pub axlrust_exec: bool,
+  /// Compute UDP checksums of encapsulated packets.  Some middleboxes drop
+  /// IPv4 UDP datagrams without one.  Rejected with
+  /// [`ChecksumMode::None`], which leaves it to the NIC.
+  #[cfg_attr(feature = "serde", serde(default))]
+  pub udp_checksum: bool,
+  /// Which checksums of encapsulated IPv4 packets are computed here.  With
+  /// checksum offload the NIC fills in the ones left zero.
+  #[cfg_attr(feature = "serde", serde(default))]
+  pub checksum_mode: ChecksumMode,
+  /// TTL of encapsulated IPv4 packets.
+  #[cfg_attr(feature = "serde", serde(default = "default_ttl"))]
+  pub ttl: u8,
+  /// DSCP of encapsulated IPv4 packets, 0-63.
+  #[cfg_attr(feature = "serde", serde(default))]
+  pub dscp: u8,
+  /// Take DSCP and ECN of encapsulated IPv4 packets from the datagram they
+  /// carry if that is an IPv4 packet itself, `dscp` is the fallback.
+  #[cfg_attr(feature = "serde", serde(default))]
+  pub copy_inner_tos: bool,
+  /// Leave the don't fragment flag of encapsulated IPv4 packets clear.
+  #[cfg_attr(feature = "serde", serde(default))]
+  pub allow_fragmentation: bool,
+  /// Record every packet sent to or received from the outside in this pcap
+  /// file.  Meant for debugging, it slows down forwarding.
+  #[cfg_attr(feature = "serde", serde(default))]
+  pub pcap_file: Option<String>,
+  /// Format of the file descriptor place holders in `axlrust_args`, with `{}`
+  /// for the socket index and doubled literal braces.  `{{fd{}}}`, i.e.
+  /// `{fd0}`, `{fd1}`, ..., if not set.
+  #[cfg_attr(feature = "serde", serde(default))]
+  pub fd_placeholder_format: Option<String>,
+  /// Accept inbound IPv4 packets whatever their UDP checksum, for NICs
+  /// whose checksum offload leaves it unfilled.
+  #[cfg_attr(feature = "serde", serde(default))]
+  pub skip_udp_checksum: bool,
+  /// Bind the outside socket to this network interface with
+  /// `SO_BINDTODEVICE`, e.g. on a multi-homed gateway.  Only for a UDP
+  /// outside socket, and may need `CAP_NET_RAW`.
+  #[cfg_attr(feature = "serde", serde(default))]
+  pub outside_bind_device: Option<String>,
+  /// Whether `outside_fd` carries whole IP packets or, as a connected UDP
+  /// socket, only the payloads.
+  #[cfg_attr(feature = "serde", serde(default))]
+  pub outside_mode: OutsideMode,
+  /// Connect the outside socket to the Unix socket bound at this path
+  /// before forwarding, unless it is connected already.
+  #[cfg_attr(feature = "serde", serde(default))]
+  pub outside_peer: Option<PathBuf>,
+  /// Serve the counters in the Prometheus text format at `/metrics` on
+  /// this address.
+  #[cfg_attr(feature = "serde", serde(default))]
+  pub metrics_addr: Option<SocketAddr>,
+}
+
+fn default_batch() -> usize {
+  32
+}
+
+fn default_send_backlog() -> usize {
+  64
+}
+
+fn default_socket_buffer() -> usize {
+  2_000_000
+}
+
+fn default_ttl() -> u8 {
+  64
+}
+
+fn default_max_datagram() -> usize {
+  DEFAULT_MAX_DATAGRAM
+}
+
+impl TunnelInserterConfig {
+  /// Start a config from the required fields up, leaving everything else at
+  /// its default.
+  pub fn builder() -> TunnelInserterConfigBuilder {
+    TunnelInserterConfigBuilder::default()
+  }
+
+  /// Parse a config from a JSON object whose keys are the field names.
+  #[cfg(feature = "serde")]
+  pub fn from_json(json: &str) -> Result<Self, String> {
+    serde_json::from_str(json).map_err(|e| format!("Invalid JSON config: {e}"))
+  }
+
+  fn check_port_counts(&self) -> Result<(), String> {
+    if self.local_ports.len() != self.remote_ports.len() {
+      return Err("Need the same number of --local-port as --remote-port".to_string());
+    }
+    if !self.remote_addrs.is_empty() && self.remote_addrs.len() != self.local_ports.len() {
+      return Err(format!(
+        "Got {} remote address(es) for {} port pair(s)",
+        self.remote_addrs.len(),
+        self.local_ports.len()
+      ));
+    }
+    Ok(())
+  }
+
+  /// Datagrams have to fit into an IP packet along with the headers added
+  /// here.
+  fn check_sizes(&self) -> Result<(), String> {
+    if self.max_datagram > MAX_PAYLOAD {
+      return Err(format!(
+        "--max-datagram {} exceeds {MAX_PAYLOAD}, the most a packet carries with its headers",
+        self.max_datagram
+      ));
+    }
+    if let Some(c) = self.coalesce.as_ref().filter(|c| c.max_bytes > MAX_PAYLOAD) {
+      return Err(format!("--coalesce-bytes {} exceeds {MAX_PAYLOAD}, the most a packet carries", c.max_bytes));
+    }
+    if let Some(pad) = self.pad_to.filter(|&pad| pad > MAX_PAYLOAD) {
+      return Err(format!("--pad-to {pad} exceeds {MAX_PAYLOAD}, the most a packet carries"));
+    }
+    Ok(())
+  }
+}
+
+/// Largest payload of a packet built for the outside: the longest IP packet
+/// less the longest headers.
+const MAX_PAYLOAD: usize = u16::MAX as usize - MAX_HEADERS_LEN;
+
+/// How long an AxlRust process gets to exit after SIGTERM before it is killed.
+const TUNNEL_STOP_GRACE: Duration = Duration::from_secs(5);
+
+/// Chainable construction of a [`TunnelInserterConfig`] with the defaults of
+/// the command line for every field not set here.  The returned config can be
+/// adjusted further through its public fields.
+#[derive(Debug, Default)]
+pub struct TunnelInserterConfigBuilder {
+  outside_fd: Option<i32>,
+  control_fd: Option<i32>,
+  local_addr: Option<IpAddr>,
+  remote_addr: Option<IpAddr>,
+  local_ports: Vec<u16>,
+  remote_ports: Vec<u16>,
+  remote_addrs: Vec<Option<IpAddr>>,
+  stderr_file: Option<String>,
+  axlrust_args: Vec<String>,
+}
+
+impl TunnelInserterConfigBuilder {
+  pub fn outside_fd(mut self, fd: i32) -> Self {
+    self.outside_fd = Some(fd);
+    self
+  }
+
+  pub fn control_fd(mut self, fd: i32) -> Self {
+    self.control_fd = Some(fd);
+    self
+  }
+
+  pub fn local_addr(mut self, addr: impl Into<IpAddr>) -> Self {
+    self.local_addr = Some(addr.into());
+    self
+  }
+
+  pub fn remote_addr(mut self, addr: impl Into<IpAddr>) -> Self {
+    self.remote_addr = Some(addr.into());
+    self
+  }
+
+  /// Add a socket pair for UDP traffic between `local` and `remote`.
+  pub fn add_port_pair(mut self, local: u16, remote: u16) -> Self {
+    self.local_ports.push(local);
+    self.remote_ports.push(remote);
+    self.remote_addrs.push(None);
+    self
+  }
+
+  /// Like [`Self::add_port_pair`], but towards `addr` rather than the
+  /// `remote_addr` shared by the other pairs.
+  pub fn add_port_pair_to(mut self, local: u16, remote: u16, addr: impl Into<IpAddr>) -> Self {
+    self.local_ports.push(local);
+    self.remote_ports.push(remote);
+    self.remote_addrs.push(Some(addr.into()));
+    self
+  }
+
+  pub fn stderr_file(mut self, file: impl Into<String>) -> Self {
+    self.stderr_file = Some(file.into());
+    self
+  }
+
+  /// Append one argument of the AxlRust command line.
+  pub fn axlrust_arg(mut self, arg: impl Into<String>) -> Self {
+    self.axlrust_args.push(arg.into());
+    self
+  }
+
+  pub fn build(self) -> Result<TunnelInserterConfig, String> {
+    let missing = |name: &str| format!("{name} is required");
+    let mut cfg = TunnelInserterConfig {
+      outside_fd: self.outside_fd.ok_or_else(|| missing("outside_fd"))?,
+      control_fd: self.control_fd.ok_or_else(|| missing("control_fd"))?,
+      local_addr: self.local_addr.ok_or_else(|| missing("local_addr"))?,
+      remote_addr: self.remote_addr.ok_or_else(|| missing("remote_addr"))?,
+      remote_addrs: Vec::new(),
+      local_ports: self.local_ports,
+      remote_ports: self.remote_ports,
+      stderr_file: self.stderr_file,
+      recv_batch: default_batch(),
+      send_batch: default_batch(),
+      send_backlog: default_send_backlog(),
+      zero_copy: false,
+      max_datagram: default_max_datagram(),
+      echo: false,
+      self_check: false,
+      coalesce: None,
+      max_spins: None,
+      axlrust_args: self.axlrust_args,
+      axl_config_items: Vec::new(),
+      allow_stdio_fds: false,
+      ip_id: IpIdMode::default(),
+      reject_reserved_flag: false,
+      flow_demux: None,
+      return_ports: Vec::new(),
+      rt_sched: None,
+      drop_empty: false,
+      pair_names: Vec::new(),
+      pad_to: None,
+      max_runtime: None,
+      idle_timeout: None,
+      link_layer: LinkLayer::default(),
+      allowed_src_ports: None,
+      allowed_dst_ports: None,
+      spoof_guard: None,
+      rate_limit: None,
+      socket_buffer: default_socket_buffer(),
+      rcvbuf: None,
+      sndbuf: None,
+      seq_window: None,
+      min_ttl: None,
+      axlrust_exec: false,
+      udp_checksum: false,
+      checksum_mode: ChecksumMode::Full,
+      ttl: default_ttl(),
+      dscp: 0,
+      copy_inner_tos: false,
+      allow_fragmentation: false,
+      pcap_file: None,
+      fd_placeholder_format: None,
+      skip_udp_checksum: false,
+      outside_bind_device: None,
+      outside_mode: OutsideMode::RawIp,
+      outside_peer: None,
+      metrics_addr: None,
+    };
+    if cfg.axlrust_args.is_empty() {
+      return Err(missing("At least one axlrust_arg"));
+    }
+    // Only spell out every pair's address if some differ.
+    if self.remote_addrs.iter().any(Option::is_some) {
+      let addrs = self.remote_addrs.iter().map(|a| a.unwrap_or(cfg.remote_addr));
+      cfg.remote_addrs = addrs.collect();
+    }
+    cfg.check_port_counts()?;
+    cfg.check_sizes()?;
+    Ok(cfg)
+  }
+}
+
+/// Expand port tokens, each a single port like `443` or an ascending range
+/// like `8000-8010`, into the list of ports in order.
+pub fn expand_ports<S: AsRef<str>>(tokens: &[S]) -> Result<Vec<u16>, String> {
+  let port = |p: &str| p.parse::<u16>().map_err(|e| format!("bad port {p:?}: {e}"));
+  let mut ports = Vec::new();
+  for token in tokens {
+    let token = token.as_ref();
+    match token.split_once('-') {
+      Some((lo, hi)) => {
+        let (lo, hi) = (port(lo)?, port(hi)?);
+        if lo > hi {
+          return Err(format!("port range {token} is not ascending"));
+        }
+        ports.extend(lo..=hi);
+      }
+      None => ports.push(port(token)?),
+    }
+  }
+  Ok(ports)
+}
+
+/// Expand the local and remote port tokens of [`expand_ports`], which have
+/// to pair up one to one.
+pub fn expand_port_pairs<S: AsRef<str>>(local: &[S], remote: &[S]) -> Result<(Vec<u16>, Vec<u16>), String> {
+  let local_ports = expand_ports(local).map_err(|e| format!("--local-ports: {e}"))?;
+  let remote_ports = expand_ports(remote).map_err(|e| format!("--remote-ports: {e}"))?;
+  if local_ports.len() != remote_ports.len() {
+    return Err(format!(
+      "--local-ports expand to {} port(s) but --remote-ports to {}",
+      local_ports.len(),
+      remote_ports.len()
+    ));
+  }
+  Ok((local_ports, remote_ports))
+}
+
+/// Parse a ports file into local and remote ports, one `LOCAL:REMOTE` pair
+/// per line.  Blank lines and everything after a `#` are ignored.
+pub fn parse_ports_file(text: &str) -> Result<(Vec<u16>, Vec<u16>), String> {
+  let mut local_ports = Vec::new();
+  let mut remote_ports = Vec::new();
+  for (n, line) in text.lines().enumerate() {
+    let line = line.split('#').next().unwrap().trim();
+    if line.is_empty() {
+      continue;
+    }
+    let n = n + 1;
+    let (local, remote) = line
+      .split_once(':')
+      .ok_or_else(|| format!("line {n}: expected LOCAL:REMOTE, got {line:?}"))?;
+    let port = |p: &str, what: &str| {
+      p.trim()
+        .parse::<u16>()
+        .map_err(|e| format!("line {n}: bad {what} port {:?}: {e}", p.trim()))
+    };
+    local_ports.push(port(local, "local")?);
+    remote_ports.push(port(remote, "remote")?);
+  }
+  Ok((local_ports, remote_ports))
+}
+
+/// Parse return port mappings, each `LOCAL:REMOTE=LOCAL:REMOTE` with the
+/// port pair on the left and the destination and source ports its inbound
+/// packets arrive with on the right.
+pub fn parse_return_ports<S: AsRef<str>>(tokens: &[S]) -> Result<Vec<(PortPair, PortPair)>, String> {
+  let pair = |s: &str| {
+    let (local, remote) = s.split_once(':')?;
+    Some(PortPair {
+      local: local.parse().ok()?,
+      remote: remote.parse().ok()?,
+    })
+  };
+  tokens
+    .iter()
+    .map(|token| {
+      let token = token.as_ref();
+      token
+        .split_once('=')
+        .and_then(|(out, back)| Some((pair(out)?, pair(back)?)))
+        .ok_or_else(|| format!("expected LOCAL:REMOTE=LOCAL:REMOTE, got {token:?}"))
+    })
+    .collect()
+}
+
+/// Sanity checks on the inherited descriptors, done before taking ownership of
+/// them.
+fn check_inherited_fds(outside_fd: RawFd, control_fd: RawFd, allow_stdio: bool) -> Result<(), String> {
+  if outside_fd == control_fd {
+    return Err(format!(
+      "--outside and --control are the same descriptor ({outside_fd})"
+    ));
+  }
+  for (name, fd) in [("--outside", outside_fd), ("--control", control_fd)] {
+    if fd < 0 {
+      return Err(format!("{name} descriptor {fd} is invalid"));
+    }
+    if fd <= 2 && !allow_stdio {
+      return Err(format!(
+        "{name} descriptor {fd} is stdin/stdout/stderr; pass --allow-stdio-fds if intended"
+      ));
+    }
+  }
+  Ok(())
+}
+
+/// The text around the socket index of a file descriptor place holder, `{fd`
+/// and `}` unless configured otherwise.
+#[derive(Debug, PartialEq)]
+struct FdPlaceholder {
+  prefix: String,
+  suffix: String,
+}
+
+impl Default for FdPlaceholder {
+  fn default() -> Self {
+    Self {
+      prefix: "{fd".to_string(),
+      suffix: "}".to_string(),
+    }
+  }
+}
+
+impl FdPlaceholder {
+  /// Parse a format string in the style of `format!`: a single `{}` stands
+  /// for the socket index, `{{` and `}}` for literal braces.  The index needs
+  /// text in front of it, or every number in the arguments would match.
+  fn parse(format: &str) -> Result<Self, String> {
+    let invalid = |why: &str| format!("Invalid fd place holder format {format:?}: {why}");
+    let mut prefix = String::new();
+    let mut suffix = String::new();
+    let mut seen_index = false;
+    let mut chars = format.chars().peekable();
+    while let Some(c) = chars.next() {
+      let out = if seen_index { &mut suffix } else { &mut prefix };
+      match (c, chars.peek()) {
+        ('{', Some('{')) | ('}', Some('}')) => {
+          chars.next();
+          out.push(c);
+        }
+        ('{', Some('}')) if !seen_index => {
+          chars.next();
+          seen_index = true;
+        }
+        ('{', Some('}')) => return Err(invalid("more than one {}")),
+        ('{' | '}', _) => return Err(invalid("literal braces must be doubled")),
+        _ => out.push(c),
+      }
+    }
+    if !seen_index {
+      return Err(invalid("no {} for the socket index"));
+    }
+    if prefix.is_empty() {
+      return Err(invalid("no text before the socket index"));
+    }
+    Ok(Self { prefix, suffix })
+  }
+
+  fn render(&self, j: usize) -> String {
+    format!("{}{j}{}", self.prefix, self.suffix)
+  }
+}
+
+/// Outcome of substituting the `{fdN}` place holders in the AxlRust arguments.
+#[derive(Debug, PartialEq)]
+struct FdSubstitution {
+  args: Vec<String>,
+  /// Indices of created sockets that no argument refers to.
+  unused: Vec<usize>,
+}
+
+/// Replace every place holder for socket `N`, `{fdN}` by default, in `args`
+/// with `fds[N]`.  A place holder without a corresponding socket is an error;
+/// sockets without a place holder are reported in [`FdSubstitution::unused`].
+fn substitute_fd_placeholders(
+  args: &[String],
+  fds: &[RawFd],
+  placeholder: &FdPlaceholder,
+) -> Result<FdSubstitution, String> {
+  let FdPlaceholder { prefix, suffix } = placeholder;
+  let mut referenced = vec![false; fds.len()];
+  let mut out = Vec::with_capacity(args.len());
+  for arg in args {
+    let mut sr = String::with_capacity(arg.len());
+    let mut rest = arg.as_str();
+    while let Some(start) = rest.find(prefix.as_str()) {
+      sr.push_str(&rest[..start]);
+      let tail = &rest[start + prefix.len()..];
+      let digits = tail.bytes().take_while(u8::is_ascii_digit).count();
+      if digits == 0 || !tail[digits..].starts_with(suffix.as_str()) {
+        // Not a place holder, keep it verbatim.
+        sr.push_str(prefix);
+        rest = tail;
+        continue;
+      }
+      let j: usize = tail[..digits]
+        .parse()
+        .map_err(|_| format!("Invalid place holder in argument {arg:?}"))?;
+      let fd = fds.get(j).ok_or_else(|| {
+        format!(
+          "Argument {arg:?} references {} but only {} socket(s) were created",
+          placeholder.render(j),
+          fds.len()
+        )
+      })?;
+      referenced[j] = true;
+      sr.push_str(&fd.to_string());
+      rest = &tail[digits + suffix.len()..];
+    }
+    sr.push_str(rest);
+    out.push(sr);
+  }
+  Ok(FdSubstitution {
+    args: out,
+    unused: (0..fds.len()).filter(|&j| !referenced[j]).collect(),
+  })
+}
+
+/// Clear `FD_CLOEXEC` on the AxlRust ends of the socket pairs, except the
+/// `unused` ones, and return their descriptors.  Sockets no argument refers
+/// to keep the flag, so they do not leak into whatever AxlRust executes.
+fn share_referenced_fds(rsocks: &[UnixDatagram], unused: &[usize]) -> Result<Vec<RawFd>, String> {
+  let mut fds = Vec::with_capacity(rsocks.len());
+  for (j, sock) in rsocks.iter().enumerate() {
+    if unused.contains(&j) {
+      continue;
+    }
+    set_cloexec(sock.as_raw_fd(), false)
+      .map_err(|e| format!("Can't clear FD_CLOEXEC on a socket for AxlRust: {e}"))?;
+    fds.push(sock.as_raw_fd());
+  }
+  Ok(fds)
+}
+
+/// Reject port pairs listed twice, whose inbound traffic could only reach
+/// one of the sockets.  A local port may be shared between pairs with
+/// different remote ports or addresses, inbound packets tell them apart by
+/// source port and address.
+fn check_duplicate_pairs(
+  port_pairs: &[PortPair],
+  remote_addrs: &[IpAddr],
+  names: &[String],
+) -> Result<(), String> {
+  let mut seen = HashMap::new();
+  for (j, (&pp, &addr)) in port_pairs.iter().zip(remote_addrs).enumerate() {
+    if let Some(&first) = seen.get(&(addr, pp)) {
+      return Err(format!(
+        "Port pair {} duplicates {}",
+        describe_pair(j, pp, names),
+        describe_pair(first, pp, names)
+      ));
+    }
+    seen.insert((addr, pp), j);
+  }
+  Ok(())
+}
+
+/// The warning if the kernel cut socket buffer option `name` well below what
+/// was asked for.  `applied` is what it reports, twice the size in effect,
+/// so less than `asked` means less than half the request took effect.
+fn check_buffer_size(name: &str, sysctl: &str, asked: usize, applied: usize) -> Option<String> {
+  (applied < asked).then(|| {
+    let effective = applied / 2;
+    format!("{name} is {effective}, below the {asked} asked for; raise {sysctl} (sysctl -w {sysctl}={asked})")
+  })
+}
+
+/// Check that every `(lsock, rsock)` pair passes datagrams both ways.
+fn check_socket_pairs(
+  port_pairs: &[PortPair],
+  names: &[String],
+  lsocks: &[UnixDatagram],
+  rsocks: &[UnixDatagram],
+) -> Result<(), String> {
+  for (j, (lsock, rsock)) in lsocks.iter().zip(rsocks).enumerate() {
+    probe_socket_pair(lsock, rsock).map_err(|e| {
+      format!(
+        "Socket pair {} failed the self-check: {e}",
+        describe_pair(j, port_pairs[j], names)
+      )
+    })?;
+  }
+  Ok(())
+}
+
+/// Append `items` to the AxlRust arguments as `-x key=value` options.
+fn with_config_items(mut args: Vec<String>, items: &[(String, String)]) -> Vec<String> {
+  for (key, value) in items {
+    args.push("-x".to_string());
+    args.push(format!("{key}={value}"));
+  }
+  args
 }
 
 fn build_tunnel_args(args: &[String]) -> TunnelArgs {
@@ -87,110 +828,1225 @@ fn build_tunnel_args(args: &[String]) -> TunnelArgs {
   }
 }
 
+/// Entry point of the tunnel component, run on its own thread by
+/// [`TunnelInserter::run`].  Defaults to [`axl_tunnel_app`].
+pub type TunnelApp = fn(&TunnelArgs);
+
+/// Message of a panic payload, which usually is a `&str` or a `String`.
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+  if let Some(s) = payload.downcast_ref::<&str>() {
+    s
+  } else if let Some(s) = payload.downcast_ref::<String>() {
+    s
+  } else {
+    "unknown payload"
+  }
+}
+
+/// The running tunnel component.
+enum Tunnel {
+  Thread(JoinHandle<()>),
+  Process(Child),
+}
+
 /// Tunnel inserter logic which was previously implemented in `main.rs`.
 pub struct TunnelInserter {
   cfg: TunnelInserterConfig,
+  stats: Arc<ForwardStats>,
+  shutdown: Arc<AtomicBool>,
+  tunnel_app: TunnelApp,
+  trace_hook: Option<TraceHook>,
 }
 
 impl TunnelInserter {
   pub fn new(cfg: TunnelInserterConfig) -> Self {
-    Self { cfg }
+    Self {
+      cfg,
+      stats: Arc::new(ForwardStats::default()),
+      shutdown: Arc::new(AtomicBool::new(false)),
+      tunnel_app: axl_tunnel_app,
+      trace_hook: None,
+    }
+  }
+
+  /// Replace the tunnel entry point, e.g. with a stub in tests.
+  pub fn with_tunnel_app(mut self, tunnel_app: TunnelApp) -> Self {
+    self.tunnel_app = tunnel_app;
+    self
+  }
+
+  /// Count into `stats` instead of fresh counters, e.g. ones from a
+  /// [`StatsRegistry`] that aggregates several inserters in one process.
+  pub fn with_stats(mut self, stats: Arc<ForwardStats>) -> Self {
+    self.stats = stats;
+    self
+  }
+
+  /// Report every packet passing through the forwarding loop to `hook`.
+  pub fn with_trace_hook(mut self, hook: TraceHook) -> Self {
+    self.trace_hook = Some(hook);
+    self
+  }
+
+  /// Counters of the forwarding loop.  The handle stays valid after
+  /// [`TunnelInserter::run`] consumed the inserter.
+  pub fn stats(&self) -> Arc<ForwardStats> {
+    self.stats.clone()
+  }
+
+  /// Flag which makes [`TunnelInserter::run`] stop forwarding and return
+  /// once the tunnel thread finished, as an alternative to closing the
+  /// control pipe.  Set it from any thread.
+  pub fn shutdown_handle(&self) -> Arc<AtomicBool> {
+    self.shutdown.clone()
   }
 
   /// Run the tunnel inserter.  This function blocks until the control pipe is
-  /// closed.
+  /// closed or the shutdown handle is set.
+  ///
+  /// Takes ownership of `outside_fd` and `control_fd` and closes them when
+  /// it returns, also on error.  Only descriptors failing the basic checks
+  /// (the same one twice, stdio, or not open) are left alone.
   pub fn run(self) -> Result<(), String> {
+    let cfg_check = self.cfg.check_port_counts().and_then(|()| self.cfg.check_sizes());
     let TunnelInserterConfig {
       outside_fd,
       control_fd,
       local_addr,
       remote_addr,
+      remote_addrs,
       mut local_ports,
       mut remote_ports,
       stderr_file,
+      recv_batch,
+      send_batch,
+      send_backlog,
+      zero_copy,
+      max_datagram,
+      echo,
+      self_check,
+      coalesce,
+      max_spins,
       axlrust_args,
+      axl_config_items,
+      allow_stdio_fds,
+      ip_id,
+      reject_reserved_flag,
+      flow_demux,
+      return_ports,
+      rt_sched,
+      drop_empty,
+      pair_names,
+      pad_to,
+      max_runtime,
+      idle_timeout,
+      link_layer,
+      allowed_src_ports,
+      allowed_dst_ports,
+      spoof_guard,
+      rate_limit,
+      socket_buffer,
+      rcvbuf,
+      sndbuf,
+      seq_window,
+      min_ttl,
+      axlrust_exec,
+      udp_checksum,
+      checksum_mode,
+      ttl,
+      dscp,
+      copy_inner_tos,
+      allow_fragmentation,
+      pcap_file,
+      fd_placeholder_format,
+      skip_udp_checksum,
+      outside_bind_device,
+      outside_mode,
+      outside_peer,
+      metrics_addr,
     } = self.cfg;
 
-    if local_ports.len() != remote_ports.len() {
-      return Err("Need the same number of --local-port as --remote-port".to_string());
+    check_inherited_fds(outside_fd, control_fd, allow_stdio_fds)?;
+    // Outside sockets coming from lightway.  Setting the flags first catches
+    // descriptors the parent already closed, before we take ownership of
+    // them.  From here on every error return closes them.
+    set_cloexec(outside_fd, true).map_err(|e| format!("Bad outside fd {outside_fd}: {e}"))?;
+    let fd_outside: Box<dyn OutsideSocket> = match outside_mode {
+      OutsideMode::RawIp => Box::new(unsafe { UnixDatagram::from_raw_fd(outside_fd) }),
+      OutsideMode::Udp => Box::new(unsafe { UdpSocket::from_raw_fd(outside_fd) }),
+    };
+    set_cloexec(control_fd, true).map_err(|e| format!("Bad control fd {control_fd}: {e}"))?;
+    let fd_pipe = File::from(unsafe { OwnedFd::from_raw_fd(control_fd) });
+    // Also for configs built by hand rather than by the builder.
+    cfg_check?;
+
+    let remote_addrs = if remote_addrs.is_empty() {
+      vec![remote_addr; local_ports.len()]
+    } else {
+      remote_addrs
+    };
+    if let Some(addr) = remote_addrs
+      .iter()
+      .chain([&remote_addr])
+      .find(|a| a.is_ipv6() != local_addr.is_ipv6())
+    {
+      return Err(format!(
+        "Local address {local_addr} and remote address {addr} are of different families"
+      ));
+    }
+    if local_addr.is_ipv6() && link_layer != LinkLayer::RawIp {
+      return Err("A link layer header is only supported with IPv4".to_string());
+    }
+    if !pair_names.is_empty() && pair_names.len() != local_ports.len() {
+      return Err(format!(
+        "Got {} pair name(s) for {} port pair(s)",
+        pair_names.len(),
+        local_ports.len()
+      ));
+    }
+    let return_ports: HashMap<PortPair, PortPair> = return_ports.into_iter().collect();
+    // With flow ids, inbound demux does not go by the ports at all.
+    if flow_demux.is_none() {
+      let pairs: Vec<PortPair> = local_ports
+        .iter()
+        .zip(&remote_ports)
+        .map(|(&local, &remote)| PortPair { local, remote })
+        .collect();
+      if let Some(pp) = return_ports.keys().find(|pp| !pairs.contains(pp)) {
+        return Err(format!("Return ports given for {}/{}, which is no port pair", pp.local, pp.remote));
+      }
+      // Inbound packets have to tell the port pairs apart by the ports they
+      // arrive with.
+      let inbound: Vec<PortPair> = pairs.iter().map(|pp| *return_ports.get(pp).unwrap_or(pp)).collect();
+      check_duplicate_pairs(&inbound, &remote_addrs, &pair_names)?;
+    } else if !return_ports.is_empty() {
+      return Err("Return ports don't apply to demux by flow id".to_string());
+    }
+    if recv_batch == 0 || send_batch == 0 {
+      return Err("--recv-batch and --send-batch must be at least 1".to_string());
+    }
+    if dscp > 63 {
+      return Err(format!("DSCP {dscp} is out of range 0-63"));
+    }
+    if max_datagram == 0 {
+      return Err("--max-datagram must be at least 1".to_string());
+    }
+    if udp_checksum && checksum_mode == ChecksumMode::None {
+      return Err("--udp-checksum conflicts with --checksum-mode none, which leaves the UDP checksum to the NIC".to_string());
+    }
+    if spoof_guard.is_some_and(|g| g.threshold == 0) {
+      return Err("The spoof guard threshold must be at least 1".to_string());
+    }
+    if outside_bind_device.is_some() && outside_mode != OutsideMode::Udp {
+      return Err("--outside-device needs a UDP outside socket, a Unix socket has no interface".to_string());
+    }
+    if outside_mode == OutsideMode::Udp {
+      if flow_demux.is_none() && local_ports.len() != 1 {
+        return Err("A UDP outside socket carries one port pair, or several with --flow-id-offset".to_string());
+      }
+      if pcap_file.is_some() {
+        return Err("--pcap-file needs whole IP packets on the outside socket".to_string());
+      }
+      if outside_peer.is_some() {
+        return Err("--outside-peer is a Unix socket, a UDP outside socket has to come connected".to_string());
+      }
+    }
+    if rate_limit.is_some_and(|r| r.rate == 0 || r.burst == 0) {
+      return Err("The rate limit needs a rate and a burst of at least 1".to_string());
+    }
+    if let Some(r) = rate_limit.filter(|r| r.bytes && r.burst < max_datagram as u64) {
+      return Err(format!(
+        "A rate limit burst of {} bytes would drop datagrams of up to {max_datagram} bytes for good",
+        r.burst
+      ));
+    }
+    if seq_window.is_some() && coalesce.is_none() {
+      return Err("--seq-window needs --coalesce-bytes, whose frames carry the numbers".to_string());
     }
+    if seq_window.is_some_and(|w| !(1..=LossTracker::MAX_WINDOW).contains(&w)) {
+      return Err(format!("--seq-window must be between 1 and {}", LossTracker::MAX_WINDOW));
+    }
+    if pad_to.is_some() && coalesce.is_none() {
+      return Err("--pad-to needs --coalesce-bytes, whose framing carries the real length".to_string());
+    }
+    if let Some((id, idx)) = flow_demux
+      .iter()
+      .flat_map(|d| &d.table)
+      .find(|(_, &idx)| idx >= local_ports.len())
+    {
+      return Err(format!(
+        "Flow id {id} maps to socket {idx} but only {} socket(s) are configured",
+        local_ports.len()
+      ));
+    }
+    // Catch place holders without a socket before starting anything.
+    let placeholder = match &fd_placeholder_format {
+      Some(format) => FdPlaceholder::parse(format)?,
+      None => FdPlaceholder::default(),
+    };
+    let axlrust_args = with_config_items(axlrust_args, &axl_config_items);
+    substitute_fd_placeholders(&axlrust_args, &vec![-1; local_ports.len()], &placeholder)?;
+    let pcap = match pcap_file {
+      Some(path) => {
+        let writer = PcapWriter::create(&path).map_err(|e| format!("Can't create {path}: {e}"))?;
+        info!("Recording packets to {path}");
+        Some(Arc::new(Mutex::new(writer)))
+      }
+      None => None,
+    };
+    // Scrapes see the counters from before forwarding starts, all zero.
+    let metrics = match metrics_addr {
+      Some(addr) => {
+        let pairs = local_ports
+          .iter()
+          .zip(&remote_ports)
+          .map(|(&local, &remote)| PortPair { local, remote })
+          .collect();
+        let names = pair_names.clone();
+        Some(MetricsServer::start(addr, self.stats.clone(), pairs, names, self.shutdown.clone())?)
+      }
+      None => None,
+    };
 
-    // Outside sockets coming from lightway.
-    let fd_outside = unsafe { UnixDatagram::from_raw_fd(outside_fd) };
-    let fd_pipe = File::from(unsafe { OwnedFd::from_raw_fd(control_fd) });
-    set_cloexec(outside_fd, true);
-    set_cloexec(control_fd, true);
     fd_outside
       .set_nonblocking(true)
       .expect("Failed to make socket nonblocking");
+    if let Some(device) = &outside_bind_device {
+      bind_to_device(&fd_outside.as_fd(), device).map_err(|e| match e {
+        Errno::EPERM => format!("Binding the outside socket to {device} failed, it may need CAP_NET_RAW"),
+        Errno::ENODEV => format!("No network interface named {device}"),
+        e => format!("Can't bind the outside socket to {device}: {e}"),
+      })?;
+      info!("Outside socket bound to {device}");
+    }
+    if let Some(path) = &outside_peer {
+      let path_str = path.display();
+      match connect_unix_peer(&*fd_outside, path) {
+        Ok(true) => info!("Outside socket connected to {path_str}"),
+        Ok(false) => info!("Outside socket already connected, not connecting it to {path_str}"),
+        Err(e) => return Err(format!("Can't connect the outside socket to {path_str}: {e}")),
+      }
+    }
 
     // Create inter process sockets which will be passed to AxlRust.
     let mut port_pairs: Vec<PortPair> = Vec::new();
     let mut lsocks: Vec<UnixDatagram> = Vec::new();
     let mut rsocks: Vec<UnixDatagram> = Vec::new();
+    let rcvbuf = rcvbuf.unwrap_or(socket_buffer);
+    let sndbuf = sndbuf.unwrap_or(socket_buffer);
     for (l, r) in local_ports.drain(..).zip(remote_ports.drain(..)) {
       port_pairs.push(PortPair {
         local: l,
         remote: r,
       });
       let (lsock, rsock) = UnixDatagram::pair().unwrap();
+      let mut applied = (0, 0);
       for sock in [&lsock, &rsock] {
-        setsockopt(&sock, sockopt::RcvBuf, &2_000_000).expect("Can't set SO_RCVBUF");
-        setsockopt(&sock, sockopt::SndBuf, &2_000_000).expect("Can't set SO_SNDBUF");
+        applied = (
+          set_buffer_size(sock, sockopt::RcvBuf, rcvbuf).map_err(|e| format!("Can't set SO_RCVBUF: {e}"))?,
+          set_buffer_size(sock, sockopt::SndBuf, sndbuf).map_err(|e| format!("Can't set SO_SNDBUF: {e}"))?,
+        );
+      }
+      // All pairs get the same, once is enough.
+      if lsocks.is_empty() {
+        let (r, s) = applied;
+        info!("Asked for SO_RCVBUF {rcvbuf} and SO_SNDBUF {sndbuf}, the kernel applied {r} and {s}");
+        let warnings = [
+          check_buffer_size("SO_RCVBUF", "net.core.rmem_max", rcvbuf, r),
+          check_buffer_size("SO_SNDBUF", "net.core.wmem_max", sndbuf, s),
+        ];
+        for w in warnings.into_iter().flatten() {
+          warn!("{w}");
+        }
       }
       lsock
         .set_nonblocking(true)
         .expect("Failed to make socket nonblocking");
       lsocks.push(lsock);
-      set_cloexec(rsock.as_raw_fd(), false);
       rsocks.push(rsock);
     }
 
+    if self_check {
+      check_socket_pairs(&port_pairs, &pair_names, &lsocks, &rsocks)?;
+    }
+
     // Substitute the file descriptor place holders in the axlrust arguments.
-    let argmap: HashMap<String, String> = (0..lsocks.len())
-      .map(|j| {
-        let fd = rsocks[j].as_raw_fd();
-        (format!("{{fd{j}}}"), format!("{fd}"))
-      })
-      .collect();
-    let args_interp: Vec<String> = axlrust_args
-      .iter()
-      .map(|s| {
-        let mut sr = s.clone();
-        for (k, v) in &argmap {
-          sr = sr.replace(k, v);
-        }
-        sr
-      })
-      .collect();
+    let rfds: Vec<RawFd> = rsocks.iter().map(|s| s.as_raw_fd()).collect();
+    let FdSubstitution {
+      args: args_interp,
+      unused,
+    } = substitute_fd_placeholders(&axlrust_args, &rfds, &placeholder)?;
+    for &j in &unused {
+      warn!(
+        "Socket {} is not referenced by the AxlRust arguments, keeping it from AxlRust",
+        describe_pair(j, port_pairs[j], &pair_names)
+      );
+    }
+    let shared_fds = share_referenced_fds(&rsocks, &unused)?;
 
-    // Optional stderr redirection. We simply log the invocation if a file is provided.
-    if let Some(mut f) = stderr_file.and_then(|f| File::create(f).ok()) {
+    // Optional stderr redirection.  The invocation is logged to the file, which
+    // also gets the stderr of an AxlRust process.  A thread cannot have a
+    // stderr of its own.
+    let stderr = stderr_file.as_ref().and_then(|f| File::create(f).ok());
+    if stderr.is_some() && !axlrust_exec {
+      warn!("The AxlRust thread writes to our stderr, only the invocation goes to the stderr file");
+    }
+    if let Some(mut f) = stderr.as_ref() {
       use std::io::Write;
       let _ = writeln!(f, "AxlRust invoked with args: {:?}", args_interp);
     } else {
-      println!("AxlRust invoked with args: {:?}", args_interp);
+      info!("AxlRust invoked with args: {:?}", args_interp);
     }
 
-    // Build tunnel arguments and run the tunnel in a separate thread.
-    let tunnel_args = build_tunnel_args(&args_interp);
-    let handle = std::thread::spawn(move || {
-      axl_tunnel_app(&tunnel_args);
-    });
+    // Run the tunnel as a child process, or in a separate thread.
+    let tunnel = if axlrust_exec {
+      let (program, args) = args_interp.split_first().ok_or("No AxlRust program given")?;
+      let child = spawn_process(program, args.to_vec(), &shared_fds, stderr)
+        .map_err(|e| format!("Can't start {program}: {e}"))?;
+      // The child has its own copies now.
+      drop(rsocks);
+      Tunnel::Process(child)
+    } else {
+      let tunnel_args = build_tunnel_args(&args_interp);
+      let tunnel_app = self.tunnel_app;
+      Tunnel::Thread(std::thread::spawn(move || {
+        tunnel_app(&tunnel_args);
+      }))
+    };
+
+    // Only after spawning, so the tunnel thread does not inherit the policy.
+    if let Some(sched) = rt_sched {
+      match set_current_thread(&sched) {
+        Ok(()) => info!("Forwarding with {:?} priority {}", sched.policy, sched.priority),
+        Err(e) => warn!("{e}; forwarding with normal scheduling"),
+      }
+    }
 
     // Start the forwarding logic.
     forward(
-      &fd_outside,
+      &*fd_outside,
       &fd_pipe,
       local_addr,
-      remote_addr,
+      &remote_addrs,
       &port_pairs,
       &lsocks,
+      &self.stats,
+      &ForwardOptions {
+        recv_batch,
+        send_batch,
+        send_backlog,
+        zero_copy,
+        max_datagram,
+        echo,
+        coalesce,
+        max_spins,
+        ip_id,
+        panic_dump: stderr_file.map(PathBuf::from),
+        parse: ParseOptions {
+          reject_reserved_flag,
+          link_layer,
+          verify_checksums: !skip_udp_checksum,
+        },
+        flow_demux,
+        return_ports,
+        drop_empty,
+        pad_to,
+        max_runtime,
+        idle_timeout,
+        pair_names,
+        allowed_src_ports,
+        allowed_dst_ports,
+        spoof_guard,
+        rate_limit,
+        outside_mode,
+        seq_window,
+        min_ttl,
+        udp_checksum,
+        checksum_mode,
+        ttl,
+        dscp,
+        copy_inner_tos,
+        dont_fragment: !allow_fragmentation,
+        shutdown: Some(self.shutdown.clone()),
+        pcap,
+        trace_hook: self.trace_hook,
+        ..Default::default()
+      },
     );
 
-    // Forward loop exited, wait for the AxlRust component to finish.
-    handle.join().expect("AxlRust thread panicked");
+    // Forward loop exited, wait for the AxlRust component to finish.  A
+    // process doesn't see our sockets close, so it is told to stop.
+    drop(metrics);
+    drop(lsocks);
+    match tunnel {
+      Tunnel::Thread(handle) => handle
+        .join()
+        .map_err(|e| format!("AxlRust thread panicked: {}", panic_message(&*e)))?,
+      Tunnel::Process(mut child) => {
+        let status = stop_process(&mut child, TUNNEL_STOP_GRACE)?;
+        if !status.success() {
+          return Err(format!("AxlRust {status}"));
+        }
+      }
+    }
 
     Ok(())
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::{
+    build_tunnel_args, check_duplicate_pairs, check_inherited_fds, check_socket_pairs,
+    check_buffer_size, describe_pair, expand_port_pairs, expand_ports, panic_message, parse_ports_file, parse_return_ports,
+    share_referenced_fds,
+    substitute_fd_placeholders, with_config_items, ChecksumMode, Coalesce, FdPlaceholder, FdSubstitution, IpIdMode, LinkLayer,
+    OutsideMode, StatsRegistry, TunnelInserter, TunnelInserterConfig,
+  };
+  use std::any::Any;
+  use crate::forward::PortPair;
+  use crate::udp::{create_ipv4_udp_packet, parse_ipv4_udp_packet, ParsedUdp};
+  use axl::TunnelArgs;
+  use nix::sys::socket::{send, MsgFlags};
+  use std::net::{IpAddr, Ipv4Addr, Shutdown};
+  use std::os::fd::{AsRawFd, IntoRawFd, RawFd};
+  use std::path::PathBuf;
+  use std::os::unix::net::UnixDatagram;
+  use std::sync::atomic::Ordering;
+  use std::sync::Mutex;
+  use std::time::Duration;
+
+  fn strings(args: &[&str]) -> Vec<String> {
+    args.iter().map(|s| s.to_string()).collect()
+  }
+
+  /// Outside and control descriptors for run() to take over and close.
+  fn owned_fds() -> (RawFd, RawFd) {
+    let (outside, _) = UnixDatagram::pair().unwrap();
+    let (control, _) = UnixDatagram::pair().unwrap();
+    (outside.into_raw_fd(), control.into_raw_fd())
+  }
+
+  /// Config with one port pair (2000/3000) and default options.
+  pub(crate) fn test_config(outside_fd: i32, control_fd: i32, axlrust_args: &[&str]) -> TunnelInserterConfig {
+    TunnelInserterConfig {
+      outside_fd,
+      control_fd,
+      local_addr: Ipv4Addr::new(192, 168, 12, 1).into(),
+      remote_addr: Ipv4Addr::new(192, 168, 12, 2).into(),
+      remote_addrs: Vec::new(),
+      local_ports: vec![2000],
+      remote_ports: vec![3000],
+      stderr_file: None,
+      recv_batch: 32,
+      send_batch: 32,
+      send_backlog: 64,
+      zero_copy: false,
+      max_datagram: 4096,
+      echo: false,
+      self_check: false,
+      coalesce: None,
+      max_spins: None,
+      axlrust_args: strings(axlrust_args),
+      axl_config_items: vec![],
+      allow_stdio_fds: false,
+      ip_id: IpIdMode::Zero,
+      reject_reserved_flag: false,
+      flow_demux: None,
+      return_ports: Vec::new(),
+      rt_sched: None,
+      drop_empty: false,
+      pair_names: vec![],
+      pad_to: None,
+      max_runtime: None,
+      idle_timeout: None,
+      link_layer: LinkLayer::RawIp,
+      allowed_src_ports: None,
+      allowed_dst_ports: None,
+      spoof_guard: None,
+      rate_limit: None,
+      socket_buffer: 2_000_000,
+      rcvbuf: None,
+      sndbuf: None,
+      seq_window: None,
+      min_ttl: None,
+      axlrust_exec: false,
+      udp_checksum: false,
+      checksum_mode: ChecksumMode::Full,
+      ttl: 64,
+      dscp: 0,
+      copy_inner_tos: false,
+      allow_fragmentation: false,
+      pcap_file: None,
+      fd_placeholder_format: None,
+      skip_udp_checksum: false,
+      outside_bind_device: None,
+      outside_mode: OutsideMode::RawIp,
+      outside_peer: None,
+      metrics_addr: None,
+    }
+  }
+
+  #[test]
+  fn substitutes_placeholders() {
+    let args = strings(&["--foo", "{fd0}", "--bar=x{fd1}y", "{fdx}", "{fd}"]);
+    assert_eq!(
+      substitute_fd_placeholders(&args, &[10, 11], &FdPlaceholder::default()).unwrap(),
+      FdSubstitution {
+        args: strings(&["--foo", "10", "--bar=x11y", "{fdx}", "{fd}"]),
+        unused: vec![],
+      }
+    );
+  }
+
+  #[test]
+  fn rejects_placeholder_without_socket() {
+    let args = strings(&["{fd0}", "{fd5}"]);
+    let err = substitute_fd_placeholders(&args, &[10, 11, 12], &FdPlaceholder::default()).unwrap_err();
+    assert!(err.contains("{fd5}"), "{err}");
+    assert!(err.contains("3 socket(s)"), "{err}");
+  }
+
+  #[test]
+  fn reports_unused_sockets() {
+    let args = strings(&["-c", "{fd1}"]);
+    let sub = substitute_fd_placeholders(&args, &[10, 11, 12], &FdPlaceholder::default()).unwrap();
+    assert_eq!(sub.args, strings(&["-c", "11"]));
+    assert_eq!(sub.unused, vec![0, 2]);
+  }
+
+  #[test]
+  fn unused_sockets_stay_cloexec() {
+    use nix::fcntl::{fcntl, FcntlArg, FdFlag};
+
+    let rsocks: Vec<UnixDatagram> = (0..3).map(|_| UnixDatagram::pair().unwrap().0).collect();
+    let rfds: Vec<RawFd> = rsocks.iter().map(|s| s.as_raw_fd()).collect();
+    let sub = substitute_fd_placeholders(&strings(&["-c", "{fd1}"]), &rfds, &FdPlaceholder::default()).unwrap();
+    assert_eq!(share_referenced_fds(&rsocks, &sub.unused), Ok(vec![rfds[1]]));
+    let cloexec = |fd| FdFlag::from_bits_truncate(fcntl(fd, FcntlArg::F_GETFD).unwrap()).contains(FdFlag::FD_CLOEXEC);
+    assert_eq!(rfds.iter().map(|&fd| cloexec(fd)).collect::<Vec<_>>(), [true, false, true]);
+  }
+
+  #[test]
+  fn substitutes_custom_placeholder_format() {
+    let placeholder = FdPlaceholder::parse("@fd{}@").unwrap();
+    let args = strings(&["--in={fd0}", "--out=@fd1@", "@fd@", "{{}}"]);
+    let sub = substitute_fd_placeholders(&args, &[10, 11], &placeholder).unwrap();
+    assert_eq!(sub.args, strings(&["--in={fd0}", "--out=11", "@fd@", "{{}}"]));
+    assert_eq!(sub.unused, vec![0]);
+    let err = substitute_fd_placeholders(&strings(&["@fd7@"]), &[10], &placeholder).unwrap_err();
+    assert!(err.contains("@fd7@") && err.contains("only 1 socket(s)"), "{err}");
+
+    // Literal braces are doubled, as with format!.
+    let placeholder = FdPlaceholder::parse("{{{{sock:{}}}}}").unwrap();
+    let sub = substitute_fd_placeholders(&strings(&["{{sock:1}}"]), &[10, 11], &placeholder).unwrap();
+    assert_eq!(sub.args, strings(&["11"]));
+    assert_eq!(FdPlaceholder::parse("{{fd{}}}").unwrap(), FdPlaceholder::default());
+  }
+
+  #[test]
+  fn rejects_bad_placeholder_formats() {
+    for format in ["fd", "{}", "{}fd", "fd{}{}", "{fd{}}", "fd{0}"] {
+      assert!(FdPlaceholder::parse(format).is_err(), "{format}");
+    }
+  }
+
+  #[test]
+  fn rejects_dangling_placeholder() {
+    let (outside, control) = owned_fds();
+    let cfg = test_config(outside, control, &["axl", "{fd0}", "{fd5}"]);
+    let err = TunnelInserter::new(cfg).run().unwrap_err();
+    assert!(err.contains("{fd5}"), "{err}");
+  }
+
+  /// What `fd` refers to, e.g. `socket:[1234]`, or `None` if it is closed.
+  fn fd_target(fd: RawFd) -> Option<PathBuf> {
+    std::fs::read_link(format!("/proc/self/fd/{fd}")).ok()
+  }
+
+  #[test]
+  fn closes_fds_on_bad_config() {
+    let (outside, control) = owned_fds();
+    let before = [fd_target(outside), fd_target(control)];
+    assert!(before.iter().all(Option::is_some));
+    let mut cfg = test_config(outside, control, &["axl"]);
+    cfg.remote_ports.push(3001);
+    let err = TunnelInserter::new(cfg).run().unwrap_err();
+    assert!(err.contains("same number"), "{err}");
+    // Closed, though another test may have reused the numbers meanwhile.
+    assert_ne!(fd_target(outside), before[0]);
+    assert_ne!(fd_target(control), before[1]);
+  }
+
+  #[test]
+  fn rejects_same_outside_and_control_fd() {
+    let (sock, peer) = UnixDatagram::pair().unwrap();
+    let fd = sock.as_raw_fd();
+    let cfg = test_config(fd, fd, &["axl"]);
+    let err = TunnelInserter::new(cfg).run().unwrap_err();
+    assert!(err.contains("same descriptor"), "{err}");
+    // The descriptor was not taken over (and closed) by run().
+    sock.send(b"still open").unwrap();
+    let mut buf = [0u8; 16];
+    assert_eq!(peer.recv(&mut buf).unwrap(), 10);
+  }
+
+  #[test]
+  fn rejects_unknown_outside_device() {
+    // run() takes both descriptors over and closes them on failure.
+    let sock = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    let (control, _control_peer) = UnixDatagram::pair().unwrap();
+    let mut cfg = test_config(sock.into_raw_fd(), control.into_raw_fd(), &["axl"]);
+    cfg.outside_mode = OutsideMode::Udp;
+    cfg.outside_bind_device = Some("nosuchif0".to_string());
+    let err = TunnelInserter::new(cfg).run().unwrap_err();
+    // Which of the two depends on whether the tests run with CAP_NET_RAW.
+    assert!(
+      err == "No network interface named nosuchif0" || err.ends_with("may need CAP_NET_RAW"),
+      "{err}"
+    );
+  }
+
+  #[test]
+  fn byte_rate_limit_burst_fits_a_datagram() {
+    let (outside, control) = owned_fds();
+    let mut cfg = test_config(outside, control, &["axl"]);
+    cfg.max_datagram = 2000;
+    cfg.rate_limit = Some(crate::RateLimit { rate: 10_000, burst: 1500, bytes: true });
+    let err = TunnelInserter::new(cfg).run().unwrap_err();
+    assert!(err.contains("burst of 1500 bytes would drop datagrams of up to 2000 bytes"), "{err}");
+  }
+
+  #[test]
+  fn outside_device_needs_udp_outside() {
+    let (outside, control) = owned_fds();
+    let mut cfg = test_config(outside, control, &["axl"]);
+    cfg.outside_bind_device = Some("lo".to_string());
+    let err = TunnelInserter::new(cfg).run().unwrap_err();
+    assert!(err.contains("--outside-device needs a UDP outside socket"), "{err}");
+  }
+
+  #[test]
+  fn udp_checksum_conflicts_with_offload() {
+    let (outside, control) = owned_fds();
+    let mut cfg = test_config(outside, control, &["axl"]);
+    cfg.udp_checksum = true;
+    cfg.checksum_mode = ChecksumMode::None;
+    let err = TunnelInserter::new(cfg).run().unwrap_err();
+    assert!(err.contains("--udp-checksum conflicts with --checksum-mode none"), "{err}");
+  }
+
+  #[test]
+  fn expands_port_ranges() {
+    assert_eq!(expand_ports(&["443"]).unwrap(), [443]);
+    assert_eq!(expand_ports(&["80", "443", "8000-8003"]).unwrap(), [80, 443, 8000, 8001, 8002, 8003]);
+    assert_eq!(expand_ports(&["7-7"]).unwrap(), [7]);
+    assert_eq!(expand_ports(&["5000-5500"]).unwrap().len(), 501);
+    assert_eq!(expand_ports::<&str>(&[]).unwrap(), Vec::<u16>::new());
+    assert_eq!(expand_ports(&["9-8"]).unwrap_err(), "port range 9-8 is not ascending");
+    assert!(expand_ports(&["1-70000"]).unwrap_err().contains("\"70000\""));
+    assert!(expand_ports(&["1-"]).is_err());
+    assert!(expand_ports(&["x"]).is_err());
+  }
+
+  #[test]
+  fn pairs_expanded_ports() {
+    let (local, remote) = expand_port_pairs(&["2000-2002", "2010"], &["3000", "3005-3007"]).unwrap();
+    assert_eq!(local, [2000, 2001, 2002, 2010]);
+    assert_eq!(remote, [3000, 3005, 3006, 3007]);
+    let err = expand_port_pairs(&["2000-2002"], &["3000-3001"]).unwrap_err();
+    assert_eq!(err, "--local-ports expand to 3 port(s) but --remote-ports to 2");
+    let err = expand_port_pairs(&["2000"], &["3001-3000"]).unwrap_err();
+    assert_eq!(err, "--remote-ports: port range 3001-3000 is not ascending");
+  }
+
+  #[test]
+  fn parses_ports_file() {
+    let text = "# voice\n5000:6000\n\n  5001 : 6001  # video\n\t\n5002:6002";
+    let (local, remote) = parse_ports_file(text).unwrap();
+    assert_eq!(local, [5000, 5001, 5002]);
+    assert_eq!(remote, [6000, 6001, 6002]);
+    assert_eq!(parse_ports_file("# nothing\n\n").unwrap(), (vec![], vec![]));
+  }
+
+  #[test]
+  fn rejects_bad_ports_file_lines() {
+    let err = parse_ports_file("5000:6000\n# ok\n70000:6001").unwrap_err();
+    assert!(err.starts_with("line 3: bad local port \"70000\""), "{err}");
+    let err = parse_ports_file("5000:-1").unwrap_err();
+    assert!(err.starts_with("line 1: bad remote port \"-1\""), "{err}");
+    let err = parse_ports_file("\n5000 6000").unwrap_err();
+    assert_eq!(err, "line 2: expected LOCAL:REMOTE, got \"5000 6000\"");
+    assert!(parse_ports_file("5000:6000:7000").is_err());
+  }
+
+  #[test]
+  fn parses_return_ports() {
+    let pp = |local, remote| PortPair { local, remote };
+    let map = parse_return_ports(&["2000:3000=2000:3500", "2001:3001=4001:3001"]).unwrap();
+    assert_eq!(map, [(pp(2000, 3000), pp(2000, 3500)), (pp(2001, 3001), pp(4001, 3001))]);
+    let err = parse_return_ports(&["2000:3000"]).unwrap_err();
+    assert_eq!(err, "expected LOCAL:REMOTE=LOCAL:REMOTE, got \"2000:3000\"");
+    assert!(parse_return_ports(&["2000:3000=2000:70000"]).is_err());
+
+    let (outside, control) = owned_fds();
+    let mut cfg = test_config(outside, control, &["-c", "{fd0}"]);
+    cfg.return_ports = vec![(pp(2001, 3000), pp(2001, 3500))];
+    let err = TunnelInserter::new(cfg).run().unwrap_err();
+    assert!(err.contains("2001/3000, which is no port pair"), "{err}");
+  }
+
+  #[test]
+  fn udp_outside_carries_one_pair() {
+    let (outside, control) = owned_fds();
+    let mut cfg = test_config(outside, control, &["axl"]);
+    cfg.outside_mode = OutsideMode::Udp;
+    cfg.local_ports.push(2001);
+    cfg.remote_ports.push(3001);
+    let err = TunnelInserter::new(cfg).run().unwrap_err();
+    assert!(err.contains("one port pair"), "{err}");
+  }
+
+  #[test]
+  fn rejects_closed_fds() {
+    // Far beyond anything open, so no other test can hold these.
+    let err = TunnelInserter::new(test_config(100_000, 100_001, &[])).run().unwrap_err();
+    assert!(err.contains("Bad outside fd 100000"), "{err}");
+  }
+
+  #[test]
+  fn rejects_mixed_address_families() {
+    let (outside, control) = owned_fds();
+    let cfg = TunnelInserterConfig {
+      remote_addr: "fd00::2".parse().unwrap(),
+      ..test_config(outside, control, &[])
+    };
+    let err = TunnelInserter::new(cfg).run().unwrap_err();
+    assert!(err.contains("different families"), "{err}");
+
+    let (outside, control) = owned_fds();
+    let cfg = TunnelInserterConfig {
+      remote_addrs: vec!["fd00::2".parse().unwrap()],
+      ..test_config(outside, control, &[])
+    };
+    let err = TunnelInserter::new(cfg).run().unwrap_err();
+    assert!(err.contains("remote address fd00::2"), "{err}");
+  }
+
+  #[test]
+  fn rejects_datagrams_too_long_for_a_packet() {
+    let (outside, control) = owned_fds();
+    let cfg = TunnelInserterConfig {
+      max_datagram: 65500,
+      ..test_config(outside, control, &[])
+    };
+    let err = TunnelInserter::new(cfg).run().unwrap_err();
+    assert!(err.contains("--max-datagram 65500 exceeds 65467"), "{err}");
+  }
+
+  #[test]
+  fn rejects_frames_too_long_for_a_packet() {
+    let (outside, control) = owned_fds();
+    let coalesce = Some(Coalesce { max_bytes: 1400, max_delay: Duration::from_millis(1) });
+    let cfg = TunnelInserterConfig {
+      coalesce,
+      pad_to: Some(70000),
+      ..test_config(outside, control, &[])
+    };
+    let err = TunnelInserter::new(cfg).run().unwrap_err();
+    assert!(err.contains("--pad-to 70000 exceeds 65467"), "{err}");
+
+    let (outside, control) = owned_fds();
+    let cfg = TunnelInserterConfig {
+      coalesce: Some(Coalesce { max_bytes: 65536, max_delay: Duration::from_millis(1) }),
+      ..test_config(outside, control, &[])
+    };
+    let err = TunnelInserter::new(cfg).run().unwrap_err();
+    assert!(err.contains("--coalesce-bytes 65536 exceeds 65467"), "{err}");
+  }
+
+  #[test]
+  fn rejects_stdio_fds_unless_allowed() {
+    assert!(check_inherited_fds(10, 11, false).is_ok());
+    assert!(check_inherited_fds(-1, 11, false).is_err());
+    let err = check_inherited_fds(10, 0, false).unwrap_err();
+    assert!(err.contains("--control descriptor 0"), "{err}");
+    assert!(check_inherited_fds(10, 0, true).is_ok());
+  }
+
+  #[test]
+  fn config_items_reach_tunnel_args() {
+    let items = vec![
+      ("tunnel.mtu".to_string(), "1400".to_string()),
+      ("fec.ratio".to_string(), "0.1".to_string()),
+    ];
+    let args = with_config_items(strings(&["axl", "-x", "a=b"]), &items);
+    let tunnel_args = build_tunnel_args(&args);
+    assert_eq!(
+      tunnel_args.config_item,
+      strings(&["a=b", "tunnel.mtu=1400", "fec.ratio=0.1"])
+    );
+  }
+
+  #[cfg(feature = "serde")]
+  #[test]
+  fn config_from_json() {
+    let cfg = TunnelInserterConfig::from_json(
+      r#"{
+        "outside_fd": 10,
+        "control_fd": 11,
+        "local_addr": "192.168.12.1",
+        "remote_addr": "192.168.12.2",
+        "local_ports": [2000, 2001],
+        "remote_ports": [3000, 3001],
+        "axlrust_args": ["-c", "{fd0}"],
+        "axl_config_items": [["block_size", "1200"]],
+        "ip_id": "random",
+        "coalesce": {"max_bytes": 1400, "max_delay": {"secs": 0, "nanos": 500000}}
+      }"#,
+    )
+    .unwrap();
+    assert_eq!((cfg.outside_fd, cfg.control_fd), (10, 11));
+    assert_eq!(cfg.remote_addr, Ipv4Addr::new(192, 168, 12, 2));
+    assert_eq!((cfg.local_ports, cfg.remote_ports), (vec![2000, 2001], vec![3000, 3001]));
+    assert_eq!(cfg.axlrust_args, strings(&["-c", "{fd0}"]));
+    assert_eq!(cfg.axl_config_items, vec![("block_size".to_string(), "1200".to_string())]);
+    assert_eq!(cfg.ip_id, IpIdMode::Random);
+    assert_eq!(cfg.coalesce.unwrap().max_delay, Duration::from_micros(500));
+    assert_eq!((cfg.recv_batch, cfg.send_batch), (32, 32));
+    assert!(!cfg.echo);
+
+    let err = TunnelInserterConfig::from_json(r#"{"outside_fd": 10}"#).unwrap_err();
+    assert!(err.contains("missing field `control_fd`"), "{err}");
+  }
+
+  #[test]
+  fn self_check_reports_broken_pair() {
+    let port_pairs = [
+      PortPair { local: 2000, remote: 3000 },
+      PortPair { local: 2001, remote: 3001 },
+    ];
+    let (l0, r0) = UnixDatagram::pair().unwrap();
+    let (l1, r1) = UnixDatagram::pair().unwrap();
+    l0.set_nonblocking(true).unwrap();
+    l1.set_nonblocking(true).unwrap();
+    let (lsocks, rsocks) = (vec![l0, l1], vec![r0, r1]);
+    assert_eq!(check_socket_pairs(&port_pairs, &[], &lsocks, &rsocks), Ok(()));
+
+    rsocks[1].shutdown(Shutdown::Both).unwrap();
+    let err = check_socket_pairs(&port_pairs, &[], &lsocks, &rsocks).unwrap_err();
+    assert!(err.contains("fd1 (ports 2001/3001)"), "{err}");
+    let names = strings(&["voice", "video"]);
+    let err = check_socket_pairs(&port_pairs, &names, &lsocks, &rsocks).unwrap_err();
+    assert!(err.contains("fd1 \"video\" (ports 2001/3001)"), "{err}");
+  }
+
+  #[test]
+  fn names_label_pairs() {
+    let pp = PortPair { local: 2000, remote: 3000 };
+    let names = strings(&["voice"]);
+    assert_eq!(describe_pair(0, pp, &names), "fd0 \"voice\" (ports 2000/3000)");
+    assert_eq!(describe_pair(0, pp, &[]), "fd0 (ports 2000/3000)");
+
+    let (outside, control) = owned_fds();
+    let mut cfg = test_config(outside, control, &["-c", "{fd0}"]);
+    cfg.pair_names = strings(&["voice", "video"]);
+    let err = TunnelInserter::new(cfg).run().unwrap_err();
+    assert!(err.contains("2 pair name(s) for 1 port pair(s)"), "{err}");
+  }
+
+  #[test]
+  fn builder_fills_in_defaults() {
+    let cfg = TunnelInserterConfig::builder()
+      .outside_fd(10)
+      .control_fd(11)
+      .local_addr(Ipv4Addr::new(192, 168, 12, 1))
+      .remote_addr(Ipv4Addr::new(192, 168, 12, 2))
+      .add_port_pair(2000, 3000)
+      .axlrust_arg("axl")
+      .axlrust_arg("{fd0}")
+      .build()
+      .unwrap();
+    let expected = test_config(10, 11, &["axl", "{fd0}"]);
+    assert_eq!(format!("{cfg:?}"), format!("{expected:?}"));
+
+    let err = TunnelInserterConfig::builder().outside_fd(10).build().unwrap_err();
+    assert_eq!(err, "control_fd is required");
+
+    let other = Ipv4Addr::new(192, 168, 12, 3);
+    let cfg = TunnelInserterConfig::builder()
+      .outside_fd(10)
+      .control_fd(11)
+      .local_addr(Ipv4Addr::new(192, 168, 12, 1))
+      .remote_addr(Ipv4Addr::new(192, 168, 12, 2))
+      .add_port_pair(2000, 3000)
+      .add_port_pair_to(2000, 3000, other)
+      .axlrust_arg("axl")
+      .build()
+      .unwrap();
+    assert_eq!(cfg.remote_addrs, [cfg.remote_addr, other.into()]);
+
+    let (outside, control) = owned_fds();
+    let mut cfg = test_config(outside, control, &["axl"]);
+    cfg.remote_ports.push(3001);
+    let err = TunnelInserter::new(cfg).run().unwrap_err();
+    assert!(err.contains("same number"), "{err}");
+  }
+
+  #[test]
+  fn rejects_duplicate_port_pairs() {
+    let pp = |local, remote| PortPair { local, remote };
+    let a: IpAddr = Ipv4Addr::new(192, 168, 12, 2).into();
+    let b: IpAddr = Ipv4Addr::new(192, 168, 12, 3).into();
+    let pairs = [pp(2000, 3000), pp(2001, 3001), pp(2000, 3000)];
+    let err = check_duplicate_pairs(&pairs, &[a; 3], &[]).unwrap_err();
+    assert_eq!(err, "Port pair fd2 (ports 2000/3000) duplicates fd0 (ports 2000/3000)");
+    // Sharing one side is fine, the other port tells the pairs apart.
+    let pairs = [pp(2000, 3000), pp(2000, 3001), pp(2001, 3001)];
+    assert!(check_duplicate_pairs(&pairs, &[a; 3], &[]).is_ok());
+    // So is the remote address.
+    assert!(check_duplicate_pairs(&[pp(2000, 3000), pp(2000, 3000)], &[a, b], &[]).is_ok());
+
+    let (outside, control) = owned_fds();
+    let mut cfg = test_config(outside, control, &["-c", "{fd0}"]);
+    cfg.local_ports.push(2000);
+    cfg.remote_ports.push(3000);
+    let err = TunnelInserter::new(cfg).run().unwrap_err();
+    assert!(err.contains("duplicates fd0"), "{err}");
+  }
+
+  static STUB_CONFIG: Mutex<Option<String>> = Mutex::new(None);
+
+  /// Stand-in for the Axl tunnel: records its `--config` argument, which the
+  /// test sets to `{fd0}`, and sends one datagram into that socket.
+  fn stub_tunnel_app(args: &TunnelArgs) {
+    let config = args.config.clone().unwrap();
+    let fd: i32 = config.parse().unwrap();
+    send(fd, b"from axl", MsgFlags::empty()).unwrap();
+    *STUB_CONFIG.lock().unwrap() = Some(config);
+  }
+
+  #[test]
+  fn warns_about_clamped_buffer_sizes() {
+    // Reported doubled, so exactly what was asked for.
+    assert_eq!(check_buffer_size("SO_RCVBUF", "net.core.rmem_max", 1 << 20, 2 << 20), None);
+    // Half of it.
+    assert_eq!(check_buffer_size("SO_RCVBUF", "net.core.rmem_max", 1 << 20, 1 << 20), None);
+    // Clamped to 3/8.
+    let w = check_buffer_size("SO_SNDBUF", "net.core.wmem_max", 1 << 20, 3 << 18).unwrap();
+    assert!(w.starts_with("SO_SNDBUF is 393216, below the 1048576 asked for"), "{w}");
+  }
+
+  #[test]
+  fn connects_outside_peer() {
+    let path = std::env::temp_dir().join(format!("outside_peer_{}", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let (_, control) = owned_fds();
+    let mut cfg = test_config(UnixDatagram::unbound().unwrap().into_raw_fd(), control, &["axl"]);
+    cfg.outside_peer = Some(path.clone());
+    let err = TunnelInserter::new(cfg).run().unwrap_err();
+    assert!(err.starts_with("Can't connect the outside socket to "), "{err}");
+
+    let outside_peer = UnixDatagram::bind(&path).unwrap();
+    outside_peer
+      .set_read_timeout(Some(Duration::from_secs(5)))
+      .unwrap();
+    let (pipe_r, pipe_w) = nix::unistd::pipe().unwrap();
+    let mut cfg = test_config(
+      UnixDatagram::unbound().unwrap().into_raw_fd(),
+      pipe_r.into_raw_fd(),
+      &["axl", "-c", "{fd0}"],
+    );
+    cfg.outside_peer = Some(path.clone());
+    let inserter = TunnelInserter::new(cfg).with_tunnel_app(stub_tunnel_app);
+    let handle = std::thread::spawn(move || inserter.run());
+    let mut buf = [0u8; 128];
+    let sz = outside_peer.recv(&mut buf).unwrap();
+    assert_eq!(parse_ipv4_udp_packet(&buf[..sz]).unwrap().payload, b"from axl");
+    drop(pipe_w);
+    handle.join().unwrap().unwrap();
+    std::fs::remove_file(&path).unwrap();
+  }
+
+  #[test]
+  fn exec_mode_reports_exit_status() {
+    let (outside, outside_peer) = UnixDatagram::pair().unwrap();
+    outside_peer
+      .set_read_timeout(Some(Duration::from_secs(5)))
+      .unwrap();
+    let (pipe_r, pipe_w) = nix::unistd::pipe().unwrap();
+    // Exits with 3 whether or not the SIGTERM at shutdown comes first.
+    let script = "trap 'exit 3' TERM; printf ready >&{fd0}; exit 3";
+    let cfg = TunnelInserterConfig {
+      axlrust_exec: true,
+      ..test_config(outside.into_raw_fd(), pipe_r.into_raw_fd(), &["sh", "-c", script])
+    };
+    let handle = std::thread::spawn(move || TunnelInserter::new(cfg).run());
+    let mut buf = [0u8; 128];
+    outside_peer.recv(&mut buf).unwrap();
+    drop(pipe_w);
+    let err = handle.join().unwrap().unwrap_err();
+    assert!(err.contains("exit status: 3"), "{err}");
+  }
+
+  #[test]
+  fn exec_mode_stops_the_child() {
+    let (outside, _outside_peer) = UnixDatagram::pair().unwrap();
+    let (pipe_r, pipe_w) = nix::unistd::pipe().unwrap();
+    let cfg = TunnelInserterConfig {
+      axlrust_exec: true,
+      ..test_config(outside.into_raw_fd(), pipe_r.into_raw_fd(), &["sleep", "1000"])
+    };
+    drop(pipe_w);
+    let started = std::time::Instant::now();
+    TunnelInserter::new(cfg).run().unwrap();
+    assert!(started.elapsed() < Duration::from_secs(5));
+  }
+
+  #[test]
+  fn exec_mode_redirects_stderr() {
+    let path = std::env::temp_dir().join(format!("axl_stderr_{}", std::process::id()));
+    let (outside, outside_peer) = UnixDatagram::pair().unwrap();
+    outside_peer
+      .set_read_timeout(Some(Duration::from_secs(5)))
+      .unwrap();
+    let (pipe_r, pipe_w) = nix::unistd::pipe().unwrap();
+    let script = "echo oops >&2; printf done >&{fd0}";
+    let cfg = TunnelInserterConfig {
+      axlrust_exec: true,
+      stderr_file: Some(path.to_str().unwrap().to_string()),
+      ..test_config(outside.into_raw_fd(), pipe_r.into_raw_fd(), &["sh", "-c", script])
+    };
+    let handle = std::thread::spawn(move || TunnelInserter::new(cfg).run());
+    let mut buf = [0u8; 128];
+    outside_peer.recv(&mut buf).unwrap();
+    drop(pipe_w);
+    handle.join().unwrap().unwrap();
+    let text = std::fs::read_to_string(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    let mut lines = text.lines();
+    assert!(lines.next().unwrap().starts_with("AxlRust invoked with args: "), "{text}");
+    assert_eq!(lines.next(), Some("oops"), "{text}");
+  }
+
+  #[test]
+  fn shutdown_handle_stops_run() {
+    let (outside, outside_peer) = UnixDatagram::pair().unwrap();
+    outside_peer
+      .set_read_timeout(Some(Duration::from_secs(5)))
+      .unwrap();
+    let (pipe_r, _pipe_w) = nix::unistd::pipe().unwrap();
+    let cfg = test_config(outside.into_raw_fd(), pipe_r.into_raw_fd(), &["axl", "-c", "{fd0}"]);
+    let inserter = TunnelInserter::new(cfg).with_tunnel_app(stub_tunnel_app);
+    let shutdown = inserter.shutdown_handle();
+    let handle = std::thread::spawn(move || inserter.run());
+
+    // Forwarding, with the control pipe still open.
+    let mut buf = [0u8; 128];
+    outside_peer.recv(&mut buf).unwrap();
+    shutdown.store(true, Ordering::Relaxed);
+    handle.join().unwrap().unwrap();
+  }
+
+  #[test]
+  fn tunnel_panic_is_an_error() {
+    fn panicking_tunnel_app(_: &TunnelArgs) {
+      panic!("tunnel blew up");
+    }
+    let (outside, _outside_peer) = UnixDatagram::pair().unwrap();
+    let (pipe_r, pipe_w) = nix::unistd::pipe().unwrap();
+    let cfg = test_config(outside.into_raw_fd(), pipe_r.into_raw_fd(), &["axl"]);
+    let inserter = TunnelInserter::new(cfg).with_tunnel_app(panicking_tunnel_app);
+    let handle = std::thread::spawn(move || inserter.run());
+    drop(pipe_w);
+    let err = handle.join().unwrap().unwrap_err();
+    assert_eq!(err, "AxlRust thread panicked: tunnel blew up");
+
+    let owned: Box<dyn Any + Send> = Box::new(format!("code {}", 7));
+    assert_eq!(panic_message(&*owned), "code 7");
+    assert_eq!(panic_message(&*Box::new(7)), "unknown payload");
+  }
+
+  #[test]
+  fn run_with_stub_tunnel() {
+    let (outside, outside_peer) = UnixDatagram::pair().unwrap();
+    outside_peer
+      .set_read_timeout(Some(Duration::from_secs(5)))
+      .unwrap();
+    let (pipe_r, pipe_w) = nix::unistd::pipe().unwrap();
+    let cfg = TunnelInserterConfig {
+      self_check: true,
+      ..test_config(outside.into_raw_fd(), pipe_r.into_raw_fd(), &["axl", "-c", "{fd0}"])
+    };
+    let inserter = TunnelInserter::new(cfg).with_tunnel_app(stub_tunnel_app);
+    let handle = std::thread::spawn(move || inserter.run());
+
+    // The datagram sent by the stub comes out encapsulated.
+    let mut buf = [0u8; 128];
+    let sz = outside_peer.recv(&mut buf).unwrap();
+    let ParsedUdp { src_ip, dst_ip, src_port, dst_port, payload: data, .. } =
+      parse_ipv4_udp_packet(&buf[..sz]).unwrap();
+    assert_eq!(
+      (src_ip, dst_ip),
+      (Ipv4Addr::new(192, 168, 12, 1), Ipv4Addr::new(192, 168, 12, 2))
+    );
+    assert_eq!((src_port, dst_port, data), (2000, 3000, &b"from axl"[..]));
+
+    drop(pipe_w);
+    handle.join().unwrap().unwrap();
+    let config = STUB_CONFIG.lock().unwrap().clone().unwrap();
+    assert!(config.parse::<i32>().is_ok(), "{config}");
+  }
+
+  #[test]
+  fn instances_share_a_registry() {
+    let registry = StatsRegistry::default();
+    let mut running = Vec::new();
+    for (label, echoes) in [("a", 1), ("b", 2)] {
+      let (outside, outside_peer) = UnixDatagram::pair().unwrap();
+      outside_peer
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+      let (pipe_r, pipe_w) = nix::unistd::pipe().unwrap();
+      let cfg = TunnelInserterConfig {
+        echo: true,
+        ..test_config(outside.into_raw_fd(), pipe_r.into_raw_fd(), &["axl", "-c", "{fd0}"])
+      };
+      let inserter = TunnelInserter::new(cfg)
+        .with_tunnel_app(stub_tunnel_app)
+        .with_stats(registry.register(label));
+      let handle = std::thread::spawn(move || inserter.run());
+      running.push((outside_peer, pipe_w, handle, echoes));
+    }
+
+    for (outside_peer, _, _, echoes) in &running {
+      let pkt = create_ipv4_udp_packet(
+        b"ping",
+        Ipv4Addr::new(192, 168, 12, 2),
+        Ipv4Addr::new(192, 168, 12, 1),
+        3000,
+        2000,
+      );
+      for _ in 0..*echoes {
+        outside_peer.send(&pkt).unwrap();
+      }
+      // The stub's datagram and the echoes, in whatever order.
+      let mut buf = [0u8; 128];
+      for _ in 0..=*echoes {
+        outside_peer.recv(&mut buf).unwrap();
+      }
+    }
+    for (_, pipe_w, handle, _) in running {
+      drop(pipe_w);
+      handle.join().unwrap().unwrap();
+    }
+
+    let by_instance: Vec<(String, u64)> = registry
+      .by_instance()
+      .into_iter()
+      .map(|(l, s)| (l, s.echoes))
+      .collect();
+    assert_eq!(by_instance, vec![("a".to_string(), 1), ("b".to_string(), 2)]);
+    assert_eq!(registry.total().echoes, 3);
+  }
+}
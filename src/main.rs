@@ -1,31 +1,282 @@
 use clap::{arg, value_parser};
-use std::net::Ipv4Addr;
+use log::LevelFilter;
+use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
+use std::ffi::c_int;
+use std::net::{IpAddr, SocketAddr};
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
 
-use tunnel_inserter::{TunnelInserter, TunnelInserterConfig};
+use tunnel_inserter::{
+    estimate_capacity, expand_port_pairs, facility_code, parse_ports_file, parse_return_ports, self_test, ChecksumMode,
+    Coalesce, FlowIdDemux, IpIdMode, LinkLayer, OutsideMode, RateLimit, RtSched, SchedPolicy, SpoofAction, SpoofGuard,
+    SyslogLogger, SyslogTarget, TunnelInserter, TunnelInserterConfig,
+};
+
+/// Parses an `ID=INDEX` flow id mapping.
+fn parse_flow_id(s: &str) -> Result<(u32, usize), String> {
+    let (id, idx) = s.split_once('=').ok_or("expected ID=INDEX")?;
+    let id = id.parse().map_err(|e| format!("bad flow id {id:?}: {e}"))?;
+    let idx = idx.parse().map_err(|e| format!("bad socket index {idx:?}: {e}"))?;
+    Ok((id, idx))
+}
+
+/// Parses an inclusive `LO-HI` port range.
+fn parse_port_range(s: &str) -> Result<RangeInclusive<u16>, String> {
+    let (lo, hi) = s.split_once('-').ok_or("expected LO-HI")?;
+    let lo: u16 = lo.parse().map_err(|e| format!("bad port {lo:?}: {e}"))?;
+    let hi: u16 = hi.parse().map_err(|e| format!("bad port {hi:?}: {e}"))?;
+    if lo > hi {
+        return Err(format!("empty port range {s}"));
+    }
+    Ok(lo..=hi)
+}
+
+/// Shutdown flag of the running inserter, for the signal handler.
+static SHUTDOWN: OnceLock<Arc<AtomicBool>> = OnceLock::new();
+
+/// Only sets the flag, which is async-signal-safe.  Anything else, like
+/// logging, happens once the forwarding loop notices.
+extern "C" fn request_shutdown(_: c_int) {
+    if let Some(flag) = SHUTDOWN.get() {
+        flag.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Make SIGTERM and SIGINT set `flag`, so that the inserter stops forwarding,
+/// waits for the tunnel and exits cleanly.  The handler is reset on first
+/// use, so a second signal kills the process as usual.
+fn install_shutdown_handler(flag: Arc<AtomicBool>) -> Result<(), String> {
+    SHUTDOWN.set(flag).map_err(|_| "Shutdown handler installed twice".to_string())?;
+    let action = SigAction::new(SigHandler::Handler(request_shutdown), SaFlags::SA_RESETHAND, SigSet::empty());
+    for sig in [Signal::SIGTERM, Signal::SIGINT] {
+        // SAFETY: the handler only touches an atomic.
+        unsafe { sigaction(sig, &action) }.map_err(|e| format!("Can't handle {sig}: {e}"))?;
+    }
+    Ok(())
+}
 
 fn main() -> Result<(), String> {
     let matches = clap::Command::new("tunnel_inserter")
         .about("Forwards raw packets and starts the BitRipple/Axl tunnel")
+        .subcommand_negates_reqs(true)
+        .subcommand(
+            clap::Command::new("self-test")
+                .long_flag("self-test")
+                .about("Loop a packet through encapsulation and decapsulation, without any socket, and print PASS or FAIL"),
+        )
+        .arg(arg!(--syslog <TARGET> "Log to syslog instead of stderr: a socket path like /dev/log, or IP:PORT of a remote server").value_parser(value_parser!(SyslogTarget)).required(false))
+        .arg(arg!(--"syslog-facility" <NAME> "Syslog facility, e.g. daemon, user or local0").default_value("daemon"))
         .arg(arg!(-o --outside <OUTSIDE_FD> "Socket corresponding to outside").value_parser(value_parser!(i32)).required(true))
         .arg(arg!(-c --control <CONTROL_FD> "Control pipe file descriptor").value_parser(value_parser!(i32)).required(true))
-        .arg(arg!(--"local-addr" <IP> "Local IPv4 address").value_parser(value_parser!(Ipv4Addr)).required(true))
-        .arg(arg!(--"remote-addr" <IP> "Remote IPv4 address").value_parser(value_parser!(Ipv4Addr)).required(true))
-        .arg(arg!(--"local-ports" <PORTS> "Local ports (space separated)").value_parser(value_parser!(u16)).num_args(1..).required(false))
-        .arg(arg!(--"remote-ports" <PORTS> "Remote ports (space separated)").value_parser(value_parser!(u16)).num_args(1..).required(false))
-        .arg(arg!(--"stderr-file" <FILE> "Destination for stderr").required(false))
+        .arg(arg!(--"local-addr" <IP> "Local IP address, 0.0.0.0 or :: to accept packets to any address").value_parser(value_parser!(IpAddr)).required(true))
+        .arg(arg!(--"remote-addr" <IP> "Remote IP address, of the same family").value_parser(value_parser!(IpAddr)).required(true))
+        .arg(arg!(--"remote-addrs" <IPS> "Remote IP address per port pair, overriding --remote-addr (space separated)").value_parser(value_parser!(IpAddr)).num_args(1..).required(false))
+        .arg(arg!(--"local-ports" <PORTS> "Local ports or LO-HI ranges (space separated)").num_args(1..).required(false))
+        .arg(arg!(--"remote-ports" <PORTS> "Remote ports or LO-HI ranges (space separated), pairing up with --local-ports").num_args(1..).required(false))
+        .arg(arg!(--"ports-file" <PATH> "File of LOCAL:REMOTE port pairs, one per line, instead of --local-ports and --remote-ports").conflicts_with_all(["local-ports", "remote-ports"]).required(false))
+        .arg(arg!(--"stderr-file" <FILE> "Destination for the stderr of an AxlRust process, see --axlrust-exec").required(false))
+        .arg(arg!(--"recv-batch" <N> "Datagrams gathered per recvmmsg call").value_parser(value_parser!(usize)).default_value("32"))
+        .arg(arg!(--"send-batch" <N> "Packets sent per sendmmsg call").value_parser(value_parser!(usize)).default_value("32"))
+        .arg(arg!(--"send-backlog" <N> "Packets held back while the outside socket is full").value_parser(value_parser!(usize)).default_value("64"))
+        .arg(arg!(--"zero-copy" "Send each local datagram with a gather write of headers and payload instead of a batched copy"))
+        .arg(arg!(--"max-datagram" <BYTES> "Drop datagrams larger than this instead of cutting them short").value_parser(value_parser!(usize)).default_value("4096"))
+        .arg(arg!(--echo "Echo inbound packets back to the outside"))
+        .arg(arg!(--"self-check" "Probe the local socket pairs before forwarding"))
+        .arg(arg!(--"coalesce-bytes" <N> "Coalesce outbound datagrams into frames of up to N bytes").value_parser(value_parser!(usize)).required(false))
+        .arg(arg!(--"coalesce-delay-us" <US> "Maximum time a datagram waits for coalescing").value_parser(value_parser!(u64)).default_value("500"))
+        .arg(arg!(--"max-spins" <N> "Stop after N consecutive epoll_wait wakeups without data").value_parser(value_parser!(usize)).required(false))
+        .arg(arg!(--"allow-stdio-fds" "Allow descriptors 0-2 as --outside/--control"))
+        .arg(arg!(--"random-ip-id" "Randomize the IPv4 identification of emitted packets"))
+        .arg(arg!(--"incrementing-ip-id" "Count up the IPv4 identification of emitted packets from a random start").conflicts_with("random-ip-id"))
+        .arg(arg!(--"reject-reserved-flag" "Drop inbound packets with the reserved IPv4 flag bit set"))
+        .arg(arg!(--"flow-id-offset" <OFFSET> "Demux inbound packets by a u32 flow id at this payload offset").value_parser(value_parser!(usize)).required(false))
+        .arg(arg!(--"flow-id" <MAPPING> "Flow id to local socket index, as ID=INDEX").value_parser(parse_flow_id).num_args(1..).required(false))
+        .arg(arg!(--"rt-priority" <PRIO> "Run the forwarding loop with real-time priority 1-99 (needs CAP_SYS_NICE)").value_parser(value_parser!(i32).range(1..=99)).required(false))
+        .arg(arg!(--"rt-round-robin" "Use SCHED_RR instead of SCHED_FIFO with --rt-priority"))
+        .arg(arg!(--"drop-empty" "Drop inbound packets with an empty payload"))
+        .arg(arg!(--"return-ports" <MAP> "Ports inbound packets of a port pair arrive with behind NAT, as LOCAL:REMOTE=DST:SRC (space separated)").num_args(1..).required(false))
+        .arg(arg!(--"pair-names" <NAMES> "Names for the port pairs used in logs and metrics (space separated)").num_args(1..).required(false))
+        .arg(arg!(--"pad-to" <N> "Zero pad coalesced frames to at least N bytes").value_parser(value_parser!(usize)).required(false))
+        .arg(arg!(--"max-runtime-secs" <SECS> "Shut down after running this many seconds").value_parser(value_parser!(u64)).required(false))
+        .arg(arg!(--"idle-timeout-secs" <SECS> "Log port pairs without traffic for this many seconds").value_parser(value_parser!(u64)).required(false))
+        .arg(arg!(--"link-layer" <MODE> "Header in front of the IPv4 header of inbound packets").value_parser(["raw_ip", "ethernet", "ethernet_vlan"]).default_value("raw_ip"))
+        .arg(arg!(--"src-port-range" <RANGE> "Drop inbound packets whose source port is outside LO-HI").value_parser(parse_port_range).required(false))
+        .arg(arg!(--"dst-port-range" <RANGE> "Drop inbound packets whose destination port is outside LO-HI").value_parser(parse_port_range).required(false))
+        .arg(arg!(--"spoof-threshold" <N> "Warn after N source IP mismatches within --spoof-window-secs").value_parser(value_parser!(usize)).required(false))
+        .arg(arg!(--"spoof-window-secs" <SECS> "Window for --spoof-threshold").value_parser(value_parser!(u64)).default_value("10"))
+        .arg(arg!(--"spoof-defend-secs" <SECS> "Also tighten inbound validation for this long when the threshold is hit").value_parser(value_parser!(u64)).required(false))
+        .arg(arg!(--"rate-limit" <RATE> "Drop datagrams from each local socket beyond RATE packets per second").value_parser(value_parser!(u64)).required(false))
+        .arg(arg!(--"rate-limit-bytes" "Count --rate-limit and --rate-burst in payload bytes rather than packets"))
+        .arg(arg!(--"rate-burst" <N> "Bucket size of --rate-limit, one second's worth by default; in bytes at least --max-datagram").value_parser(value_parser!(u64)).required(false))
+        .arg(arg!(--"socket-buffer" <BYTES> "SO_RCVBUF/SO_SNDBUF of the sockets passed to AxlRust").value_parser(value_parser!(usize)).default_value("2000000"))
+        .arg(arg!(--rcvbuf <BYTES> "SO_RCVBUF only, overriding --socket-buffer").value_parser(value_parser!(usize)).required(false))
+        .arg(arg!(--sndbuf <BYTES> "SO_SNDBUF only, overriding --socket-buffer").value_parser(value_parser!(usize)).required(false))
+        .arg(arg!(--estimate "Print a rough estimate of the sustainable packet rate and exit"))
+        .arg(arg!(--mtu <BYTES> "Packet size assumed by --estimate").value_parser(value_parser!(usize)).default_value("1500"))
+        .arg(arg!(--"seq-window" <N> "Number frames and count inbound gaps as loss, tolerating N frames of reordering").value_parser(value_parser!(u32)).required(false))
+        .arg(arg!(--"min-ttl" <TTL> "Drop inbound packets with a lower outer TTL").value_parser(value_parser!(u8)).required(false))
+        .arg(arg!(--"udp-checksum" "Compute UDP checksums of encapsulated packets, not with --checksum-mode none"))
+        .arg(arg!(--"checksum-mode" <MODE> "full: compute all checksums; ip_only: leave the IPv4 header checksum to the NIC; none: leave both to the NIC").value_parser(["full", "ip_only", "none"]).default_value("full"))
+        .arg(arg!(--"skip-udp-checksum" "Accept inbound IPv4 packets whatever their UDP checksum"))
+        .arg(arg!(--"outside-mode" <MODE> "What the outside socket carries: raw IP packets, or payloads on a connected UDP socket").value_parser(["raw_ip", "udp"]).default_value("raw_ip"))
+        .arg(arg!(--"outside-device" <IFACE> "Bind a UDP outside socket to this interface, may need CAP_NET_RAW").required(false))
+        .arg(arg!(--"outside-peer" <PATH> "Connect an unconnected outside socket to the Unix socket at this path").required(false).value_parser(value_parser!(PathBuf)))
+        .arg(arg!(--ttl <TTL> "TTL of encapsulated IPv4 packets").value_parser(value_parser!(u8)).default_value("64"))
+        .arg(arg!(--dscp <DSCP> "DSCP of encapsulated IPv4 packets").value_parser(value_parser!(u8).range(0..=63)).default_value("0"))
+        .arg(arg!(--"copy-inner-tos" "Copy DSCP and ECN of datagrams that are IPv4 packets to the outer header, --dscp for all others"))
+        .arg(arg!(--"allow-fragmentation" "Clear the don't fragment flag of encapsulated IPv4 packets"))
+        .arg(arg!(--"metrics-addr" <ADDR> "Serve Prometheus metrics at http://ADDR/metrics, e.g. 127.0.0.1:9100").value_parser(value_parser!(SocketAddr)).required(false))
+        .arg(arg!(--"pcap-file" <FILE> "Record all packets to and from the outside in this pcap file").required(false))
+        .arg(arg!(--"fd-placeholder-format" <FORMAT> "Format of the socket place holders in CMD, {} being the index; braces doubled").required(false))
+        .arg(arg!(--"axlrust-exec" "Run CMD as a program inheriting the sockets instead of the built-in tunnel"))
         .arg(arg!(<CMD> "Command to call").num_args(1..).required(true))
         .get_matches();
 
+    match matches.get_one::<SyslogTarget>("syslog") {
+        Some(target) => {
+            let name = matches.get_one::<String>("syslog-facility").unwrap();
+            let facility = facility_code(name).ok_or_else(|| format!("Unknown syslog facility {name:?}"))?;
+            // No filter syntax here, RUST_LOG can only name a level.
+            let level = std::env::var("RUST_LOG").ok().and_then(|l| l.parse().ok()).unwrap_or(LevelFilter::Info);
+            let logger = SyslogLogger::new(target, facility, level).map_err(|e| format!("Can't log to syslog: {e}"))?;
+            log::set_logger(Box::leak(Box::new(logger))).map_err(|e| e.to_string())?;
+            log::set_max_level(level);
+        }
+        // Datapath drops are logged at debug level, so they stay quiet unless
+        // RUST_LOG asks for them.
+        None => env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init(),
+    }
+
+    if matches.subcommand_matches("self-test").is_some() {
+        match self_test() {
+            Ok(()) => println!("PASS"),
+            Err(e) => {
+                println!("FAIL: {e}");
+                std::process::exit(1);
+            }
+        }
+        return Ok(());
+    }
+
+    let (local_ports, remote_ports) = match matches.get_one::<String>("ports-file") {
+        Some(path) => {
+            let text = std::fs::read_to_string(path).map_err(|e| format!("Can't read {path}: {e}"))?;
+            parse_ports_file(&text).map_err(|e| format!("{path}: {e}"))?
+        }
+        None => {
+            let tokens = |name| matches.get_many::<String>(name).map(|p| p.cloned().collect::<Vec<_>>()).unwrap_or_default();
+            expand_port_pairs(&tokens("local-ports"), &tokens("remote-ports"))?
+        }
+    };
     let cfg = TunnelInserterConfig {
         outside_fd: *matches.get_one::<i32>("outside").unwrap(),
         control_fd: *matches.get_one::<i32>("control").unwrap(),
-        local_addr: *matches.get_one::<Ipv4Addr>("local-addr").unwrap(),
-        remote_addr: *matches.get_one::<Ipv4Addr>("remote-addr").unwrap(),
-        local_ports: matches.get_many::<u16>("local-ports").map(|p| p.copied().collect()).unwrap_or_default(),
-        remote_ports: matches.get_many::<u16>("remote-ports").map(|p| p.copied().collect()).unwrap_or_default(),
+        local_addr: *matches.get_one::<IpAddr>("local-addr").unwrap(),
+        remote_addr: *matches.get_one::<IpAddr>("remote-addr").unwrap(),
+        remote_addrs: matches.get_many::<IpAddr>("remote-addrs").map(|a| a.copied().collect()).unwrap_or_default(),
+        local_ports,
+        remote_ports,
         stderr_file: matches.get_one::<String>("stderr-file").cloned(),
+        recv_batch: *matches.get_one::<usize>("recv-batch").unwrap(),
+        send_batch: *matches.get_one::<usize>("send-batch").unwrap(),
+        send_backlog: *matches.get_one::<usize>("send-backlog").unwrap(),
+        zero_copy: matches.get_flag("zero-copy"),
+        max_datagram: *matches.get_one::<usize>("max-datagram").unwrap(),
+        echo: matches.get_flag("echo"),
+        self_check: matches.get_flag("self-check"),
+        coalesce: matches.get_one::<usize>("coalesce-bytes").map(|&max_bytes| Coalesce {
+            max_bytes,
+            max_delay: Duration::from_micros(*matches.get_one::<u64>("coalesce-delay-us").unwrap()),
+        }),
+        max_spins: matches.get_one::<usize>("max-spins").copied(),
         axlrust_args: matches.get_many::<String>("CMD").unwrap().map(|s| s.to_string()).collect(),
+        axl_config_items: Vec::new(),
+        allow_stdio_fds: matches.get_flag("allow-stdio-fds"),
+        reject_reserved_flag: matches.get_flag("reject-reserved-flag"),
+        ip_id: if matches.get_flag("random-ip-id") {
+            IpIdMode::Random
+        } else if matches.get_flag("incrementing-ip-id") {
+            IpIdMode::Incrementing
+        } else {
+            IpIdMode::Zero
+        },
+        flow_demux: matches.get_one::<usize>("flow-id-offset").map(|&offset| FlowIdDemux {
+            offset,
+            table: matches.get_many::<(u32, usize)>("flow-id").map(|m| m.copied().collect()).unwrap_or_default(),
+        }),
+        return_ports: parse_return_ports(&matches.get_many::<String>("return-ports").map(|m| m.cloned().collect::<Vec<_>>()).unwrap_or_default())
+            .map_err(|e| format!("--return-ports: {e}"))?,
+        pair_names: matches.get_many::<String>("pair-names").map(|n| n.cloned().collect()).unwrap_or_default(),
+        link_layer: match matches.get_one::<String>("link-layer").unwrap().as_str() {
+            "ethernet" => LinkLayer::Ethernet,
+            "ethernet_vlan" => LinkLayer::EthernetVlan,
+            _ => LinkLayer::RawIp,
+        },
+        allowed_src_ports: matches.get_one::<RangeInclusive<u16>>("src-port-range").cloned(),
+        allowed_dst_ports: matches.get_one::<RangeInclusive<u16>>("dst-port-range").cloned(),
+        spoof_guard: matches.get_one::<usize>("spoof-threshold").map(|&threshold| SpoofGuard {
+            threshold,
+            window: Duration::from_secs(*matches.get_one::<u64>("spoof-window-secs").unwrap()),
+            action: match matches.get_one::<u64>("spoof-defend-secs") {
+                Some(&s) => SpoofAction::Defend(Duration::from_secs(s)),
+                None => SpoofAction::Warn,
+            },
+        }),
+        rate_limit: matches.get_one::<u64>("rate-limit").map(|&rate| RateLimit {
+            rate,
+            burst: matches.get_one::<u64>("rate-burst").copied().unwrap_or(rate),
+            bytes: matches.get_flag("rate-limit-bytes"),
+        }),
+        socket_buffer: *matches.get_one::<usize>("socket-buffer").unwrap(),
+        rcvbuf: matches.get_one::<usize>("rcvbuf").copied(),
+        sndbuf: matches.get_one::<usize>("sndbuf").copied(),
+        seq_window: matches.get_one::<u32>("seq-window").copied(),
+        min_ttl: matches.get_one::<u8>("min-ttl").copied(),
+        axlrust_exec: matches.get_flag("axlrust-exec"),
+        udp_checksum: matches.get_flag("udp-checksum"),
+        checksum_mode: match matches.get_one::<String>("checksum-mode").unwrap().as_str() {
+            "ip_only" => ChecksumMode::IpOnly,
+            "none" => ChecksumMode::None,
+            _ => ChecksumMode::Full,
+        },
+        skip_udp_checksum: matches.get_flag("skip-udp-checksum"),
+        outside_bind_device: matches.get_one::<String>("outside-device").cloned(),
+        outside_mode: match matches.get_one::<String>("outside-mode").unwrap().as_str() {
+            "udp" => OutsideMode::Udp,
+            _ => OutsideMode::RawIp,
+        },
+        outside_peer: matches.get_one::<PathBuf>("outside-peer").cloned(),
+        metrics_addr: matches.get_one::<SocketAddr>("metrics-addr").copied(),
+        ttl: *matches.get_one::<u8>("ttl").unwrap(),
+        dscp: *matches.get_one::<u8>("dscp").unwrap(),
+        copy_inner_tos: matches.get_flag("copy-inner-tos"),
+        allow_fragmentation: matches.get_flag("allow-fragmentation"),
+        pcap_file: matches.get_one::<String>("pcap-file").cloned(),
+        fd_placeholder_format: matches.get_one::<String>("fd-placeholder-format").cloned(),
+        max_runtime: matches.get_one::<u64>("max-runtime-secs").map(|&s| Duration::from_secs(s)),
+        idle_timeout: matches.get_one::<u64>("idle-timeout-secs").map(|&s| Duration::from_secs(s)),
+        pad_to: matches.get_one::<usize>("pad-to").copied(),
+        drop_empty: matches.get_flag("drop-empty"),
+        rt_sched: matches.get_one::<i32>("rt-priority").map(|&priority| RtSched {
+            policy: if matches.get_flag("rt-round-robin") { SchedPolicy::RoundRobin } else { SchedPolicy::Fifo },
+            priority,
+        }),
     };
 
-    TunnelInserter::new(cfg).run()
+    if matches.get_flag("estimate") {
+        let mtu = *matches.get_one::<usize>("mtu").unwrap();
+        let est = estimate_capacity(&cfg, mtu);
+        println!(
+            "{} packets of {mtu} bytes fit a socket buffer; roughly {} packets/s ({} bytes/s) per socket before drops",
+            est.packets_buffered, est.packets_per_sec, est.bytes_per_sec
+        );
+        return Ok(());
+    }
+
+    let inserter = TunnelInserter::new(cfg);
+    install_shutdown_handler(inserter.shutdown_handle())?;
+    inserter.run()
 }
@@ -0,0 +1,283 @@
+use std::fmt::Write as _;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use log::{debug, info, warn};
+
+use crate::forward::PortPair;
+use crate::stats::{ForwardStats, PairSnapshot, StatsSnapshot};
+
+/// Prefix of every exported metric name.
+const PREFIX: &str = "tunnel_inserter";
+
+/// How often the listener looks at the shutdown flags between connections.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Longest request head read, a scraper sends far less.
+const MAX_REQUEST: usize = 8192;
+
+/// Longest time to wait for a whole request head.  Requests are served on
+/// the listener thread, so a client trickling in bytes holds up every other
+/// scrape and the shutdown check until this runs out.
+const REQUEST_DEADLINE: Duration = Duration::from_secs(1);
+
+/// Render `total` and the per pair counters in the Prometheus text
+/// exposition format.  `pairs` is indexed like the local sockets, pairs
+/// without a snapshot yet export zeros.  Pairs with a name in `names` are
+/// labelled with it as well.
+pub fn render_prometheus(
+  total: &StatsSnapshot,
+  port_pairs: &[PortPair],
+  names: &[String],
+  pairs: &[PairSnapshot],
+) -> String {
+  let mut out = String::new();
+  for (name, value) in total.counters() {
+    let _ = writeln!(out, "# TYPE {PREFIX}_{name}_total counter");
+    let _ = writeln!(out, "{PREFIX}_{name}_total {value}");
+  }
+  let snaps: Vec<PairSnapshot> = (0..port_pairs.len())
+    .map(|j| pairs.get(j).copied().unwrap_or_default())
+    .collect();
+  let labels: Vec<String> = port_pairs
+    .iter()
+    .enumerate()
+    .map(|(j, pp)| {
+      let pair_name = match names.get(j) {
+        Some(n) => format!(",name=\"{}\"", escape_label(n)),
+        None => String::new(),
+      };
+      format!("{{local_port=\"{}\",remote_port=\"{}\"{pair_name}}}", pp.local, pp.remote)
+    })
+    .collect();
+  for (k, (name, _)) in PairSnapshot::default().counters().into_iter().enumerate() {
+    let _ = writeln!(out, "# TYPE {PREFIX}_pair_{name}_total counter");
+    for (labels, snap) in labels.iter().zip(&snaps) {
+      let value = snap.counters()[k].1;
+      let _ = writeln!(out, "{PREFIX}_pair_{name}_total{labels} {value}");
+    }
+  }
+  // Pairs without numbered frames yet have no loss rate to export.
+  let _ = writeln!(out, "# TYPE {PREFIX}_pair_frame_loss_ratio gauge");
+  for (labels, snap) in labels.iter().zip(&snaps) {
+    if let Some(rate) = snap.loss_rate() {
+      let _ = writeln!(out, "{PREFIX}_pair_frame_loss_ratio{labels} {rate}");
+    }
+  }
+  out
+}
+
+/// `value` with the backslashes, quotes and line breaks a label value can't
+/// hold as they are escaped.
+fn escape_label(value: &str) -> String {
+  value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// Serves the counters of one inserter at `/metrics` on its own thread, so
+/// scrapes never hold up forwarding.  Stops once the shutdown flag is set or
+/// the server is dropped, within about a second even with a client in the
+/// middle of a request.
+#[derive(Debug)]
+pub struct MetricsServer {
+  addr: SocketAddr,
+  stop: Arc<AtomicBool>,
+  thread: Option<JoinHandle<()>>,
+}
+
+impl MetricsServer {
+  pub fn start(
+    addr: SocketAddr,
+    stats: Arc<ForwardStats>,
+    port_pairs: Vec<PortPair>,
+    names: Vec<String>,
+    shutdown: Arc<AtomicBool>,
+  ) -> Result<Self, String> {
+    let listener = TcpListener::bind(addr).map_err(|e| format!("Can't listen for metrics on {addr}: {e}"))?;
+    listener
+      .set_nonblocking(true)
+      .map_err(|e| format!("Can't make the metrics listener nonblocking: {e}"))?;
+    let addr = listener.local_addr().map_err(|e| format!("Metrics listener has no address: {e}"))?;
+    info!("Serving metrics at http://{addr}/metrics");
+    let stop = Arc::new(AtomicBool::new(false));
+    let thread_stop = stop.clone();
+    let thread = std::thread::Builder::new()
+      .name("metrics".to_string())
+      .spawn(move || {
+        while !thread_stop.load(Ordering::Relaxed) && !shutdown.load(Ordering::Relaxed) {
+          match listener.accept() {
+            Ok((conn, peer)) => {
+              if let Err(e) = serve(conn, &stats, &port_pairs, &names) {
+                debug!("Metrics request from {peer} failed: {e}");
+              }
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => std::thread::sleep(POLL_INTERVAL),
+            Err(e) => {
+              warn!("Metrics listener failed: {e}");
+              std::thread::sleep(POLL_INTERVAL);
+            }
+          }
+        }
+      })
+      .map_err(|e| format!("Can't start the metrics thread: {e}"))?;
+    Ok(Self {
+      addr,
+      stop,
+      thread: Some(thread),
+    })
+  }
+
+  /// Address actually listened on, with the port filled in if 0 was asked.
+  pub fn local_addr(&self) -> SocketAddr {
+    self.addr
+  }
+}
+
+impl Drop for MetricsServer {
+  fn drop(&mut self) {
+    self.stop.store(true, Ordering::Relaxed);
+    if let Some(thread) = self.thread.take() {
+      let _ = thread.join();
+    }
+  }
+}
+
+/// Answer one HTTP request on `conn` and close it.
+fn serve(mut conn: TcpStream, stats: &ForwardStats, port_pairs: &[PortPair], names: &[String]) -> io::Result<()> {
+  conn.set_nonblocking(false)?;
+  conn.set_write_timeout(Some(Duration::from_secs(1)))?;
+  let deadline = Instant::now() + REQUEST_DEADLINE;
+  let mut head = Vec::new();
+  let mut buf = [0u8; 1024];
+  while !head.windows(4).any(|w| w == b"\r\n\r\n") && head.len() < MAX_REQUEST {
+    let left = deadline.saturating_duration_since(Instant::now());
+    if left.is_zero() {
+      return Err(io::Error::new(io::ErrorKind::TimedOut, "request head took too long"));
+    }
+    conn.set_read_timeout(Some(left))?;
+    let n = conn.read(&mut buf)?;
+    if n == 0 {
+      break;
+    }
+    head.extend_from_slice(&buf[..n]);
+  }
+  let head = String::from_utf8_lossy(&head);
+  let mut words = head.split_whitespace();
+  let (status, body) = match (words.next(), words.next()) {
+    (Some("GET"), Some("/metrics")) => (
+      "200 OK",
+      render_prometheus(&stats.snapshot(), port_pairs, names, &stats.pair_snapshot()),
+    ),
+    (Some("GET"), _) => ("404 Not Found", "Not found, try /metrics\n".to_string()),
+    _ => ("405 Method Not Allowed", "Only GET is supported\n".to_string()),
+  };
+  write!(
+    conn,
+    "HTTP/1.1 {status}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+    body.len()
+  )
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{render_prometheus, MetricsServer, REQUEST_DEADLINE};
+  use crate::forward::PortPair;
+  use crate::stats::{bump, ForwardStats, PairSnapshot, StatsSnapshot};
+  use std::io::{Read, Write};
+  use std::net::TcpStream;
+  use std::sync::atomic::{AtomicBool, Ordering};
+  use std::sync::Arc;
+  use std::time::{Duration, Instant};
+
+  fn get(addr: std::net::SocketAddr, path: &str) -> String {
+    let mut conn = TcpStream::connect(addr).unwrap();
+    write!(conn, "GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+    let mut resp = String::new();
+    conn.read_to_string(&mut resp).unwrap();
+    resp
+  }
+
+  #[test]
+  fn renders_counters_with_pair_labels() {
+    let total = StatsSnapshot {
+      echoes: 3,
+      ..Default::default()
+    };
+    let pairs = [
+      PortPair { local: 2000, remote: 3000 },
+      PortPair { local: 2001, remote: 3001 },
+    ];
+    let snaps = [PairSnapshot {
+      packets_to_outside: 7,
+      frames_received: 3,
+      frames_lost: 1,
+      ..Default::default()
+    }];
+    let text = render_prometheus(&total, &pairs, &[], &snaps);
+    assert!(text.contains("# TYPE tunnel_inserter_echoes_total counter\ntunnel_inserter_echoes_total 3\n"));
+    assert!(text.contains("tunnel_inserter_pair_packets_to_outside_total{local_port=\"2000\",remote_port=\"3000\"} 7\n"));
+    // No snapshot yet for the second pair.
+    assert!(text.contains("tunnel_inserter_pair_packets_to_outside_total{local_port=\"2001\",remote_port=\"3001\"} 0\n"));
+    assert!(text.contains("tunnel_inserter_pair_frame_loss_ratio{local_port=\"2000\",remote_port=\"3000\"} 0.25\n"));
+    assert!(!text.contains("tunnel_inserter_pair_frame_loss_ratio{local_port=\"2001\""));
+    let names = ["say \"hi\"".to_string(), "video".to_string()];
+    let named = render_prometheus(&total, &pairs, &names, &snaps);
+    assert!(named.contains("{local_port=\"2000\",remote_port=\"3000\",name=\"say \\\"hi\\\"\"} 7\n"), "{named}");
+    // Every sample line is a name, optional labels and a number.
+    for line in text.lines().filter(|l| !l.starts_with('#')) {
+      let (_, value) = line.rsplit_once(' ').unwrap();
+      value.parse::<f64>().unwrap();
+    }
+  }
+
+  #[test]
+  fn serves_metrics_until_shutdown() {
+    let stats = Arc::new(ForwardStats::default());
+    bump(&stats.frames_sent);
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let pairs = vec![PortPair { local: 2000, remote: 3000 }];
+    let names = vec!["voice".to_string()];
+    let server = MetricsServer::start("127.0.0.1:0".parse().unwrap(), stats, pairs, names, shutdown.clone()).unwrap();
+    let addr = server.local_addr();
+
+    let resp = get(addr, "/metrics");
+    assert!(resp.starts_with("HTTP/1.1 200 OK\r\n"), "{resp}");
+    assert!(resp.contains("\ntunnel_inserter_frames_sent_total 1\n"), "{resp}");
+    let labels = "{local_port=\"2000\",remote_port=\"3000\",name=\"voice\"}";
+    assert!(resp.contains(&format!("\ntunnel_inserter_pair_drops_inside_total{labels} 0\n")), "{resp}");
+    assert!(get(addr, "/").starts_with("HTTP/1.1 404"));
+
+    shutdown.store(true, Ordering::Relaxed);
+    std::thread::sleep(Duration::from_millis(300));
+    assert!(server.thread.as_ref().unwrap().is_finished());
+    drop(server);
+  }
+
+  #[test]
+  fn gives_up_on_slow_requests() {
+    let stats = Arc::new(ForwardStats::default());
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let server = MetricsServer::start("127.0.0.1:0".parse().unwrap(), stats, vec![], vec![], shutdown).unwrap();
+    let addr = server.local_addr();
+
+    // A byte every 200 ms, each well within a read timeout, would take
+    // almost 5 s for the whole head.
+    let slow = std::thread::spawn(move || {
+      let mut conn = TcpStream::connect(addr).unwrap();
+      for &b in b"GET /metrics HTTP/1.1\r\n\r\n" {
+        if conn.write_all(&[b]).is_err() {
+          break;
+        }
+        std::thread::sleep(Duration::from_millis(200));
+      }
+    });
+    std::thread::sleep(Duration::from_millis(100));
+    let start = Instant::now();
+    assert!(get(addr, "/metrics").starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(start.elapsed() < REQUEST_DEADLINE * 2, "{:?}", start.elapsed());
+    slow.join().unwrap();
+  }
+}
@@ -0,0 +1,108 @@
+use std::fs::File;
+use std::io;
+use std::os::fd::RawFd;
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::time::{Duration, Instant};
+
+use nix::sys::signal::{kill, Signal};
+use nix::unistd::Pid;
+
+use crate::sock_utils::set_cloexec;
+
+/// Run `program` with `args` as a child process which inherits the
+/// descriptors `inherit_fds` under the same numbers, with its stderr going to
+/// `stderr` if given.  The descriptors may have `FD_CLOEXEC` set in this
+/// process; it is cleared in the child only, between fork and exec.
+pub fn spawn_process(
+  program: &str,
+  args: Vec<String>,
+  inherit_fds: &[RawFd],
+  stderr: Option<File>,
+) -> io::Result<Child> {
+  let fds = inherit_fds.to_vec();
+  let mut cmd = Command::new(program);
+  cmd.args(args);
+  if let Some(f) = stderr {
+    cmd.stderr(Stdio::from(f));
+  }
+  // SAFETY: only fcntl runs in the child, which is async-signal-safe, and the
+  // closure does not allocate.
+  unsafe {
+    cmd.pre_exec(move || {
+      for &fd in &fds {
+        set_cloexec(fd, false)?;
+      }
+      Ok(())
+    });
+  }
+  cmd.spawn()
+}
+
+/// Ask `child` to exit with SIGTERM and wait for it, killing it if it is still
+/// around after `grace`.  Exiting from the SIGTERM counts as success.
+pub fn stop_process(child: &mut Child, grace: Duration) -> Result<ExitStatus, String> {
+  let wait_err = |e| format!("Can't wait for AxlRust: {e}");
+  if let Some(status) = child.try_wait().map_err(wait_err)? {
+    return Ok(status);
+  }
+  let _ = kill(Pid::from_raw(child.id() as i32), Signal::SIGTERM);
+  let deadline = Instant::now() + grace;
+  while Instant::now() < deadline {
+    if let Some(status) = child.try_wait().map_err(wait_err)? {
+      if status.signal() == Some(Signal::SIGTERM as i32) {
+        return Ok(ExitStatus::from_raw(0));
+      }
+      return Ok(status);
+    }
+    std::thread::sleep(Duration::from_millis(10));
+  }
+  let _ = child.kill();
+  child.wait().map_err(wait_err)?;
+  Err(format!("AxlRust did not exit within {grace:?} of SIGTERM, killed it"))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{spawn_process, stop_process};
+  use std::os::fd::AsRawFd;
+  use std::os::unix::net::UnixDatagram;
+  use std::time::Duration;
+
+  #[test]
+  fn child_inherits_listed_fds() {
+    // Sockets from std carry FD_CLOEXEC.
+    let (ours, theirs) = UnixDatagram::pair().unwrap();
+    ours.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let fd = theirs.as_raw_fd();
+    let script = format!("printf hello >&{fd}");
+    let mut child =
+      spawn_process("sh", vec!["-c".to_string(), script], &[fd], None).unwrap();
+    assert!(child.wait().unwrap().success());
+    let mut buf = [0u8; 16];
+    let sz = ours.recv(&mut buf).unwrap();
+    assert_eq!(&buf[..sz], b"hello");
+  }
+
+  #[test]
+  fn stop_terminates_child() {
+    let mut child =
+      spawn_process("sleep", vec!["1000".to_string()], &[], None).unwrap();
+    assert!(stop_process(&mut child, Duration::from_secs(5)).unwrap().success());
+  }
+
+  #[test]
+  fn stop_kills_child_ignoring_sigterm() {
+    let (ours, theirs) = UnixDatagram::pair().unwrap();
+    ours.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let fd = theirs.as_raw_fd();
+    let script = format!("trap '' TERM; printf ready >&{fd}; exec sleep 1000");
+    let mut child = spawn_process("sh", vec!["-c".to_string(), script], &[fd], None).unwrap();
+    // Only signal once the trap is in place.
+    let mut buf = [0u8; 16];
+    ours.recv(&mut buf).unwrap();
+    let err = stop_process(&mut child, Duration::from_millis(200)).unwrap_err();
+    assert!(err.contains("killed"), "{err}");
+    assert!(child.try_wait().unwrap().is_some());
+  }
+}
@@ -0,0 +1,92 @@
+use std::io;
+
+/// Real-time scheduling policy for the forwarding thread.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize), serde(rename_all = "snake_case"))]
+pub enum SchedPolicy {
+  /// `SCHED_FIFO`: runs until it blocks or a higher priority thread wakes up.
+  Fifo,
+  /// `SCHED_RR`: like `Fifo`, but time-sliced among threads of equal priority.
+  RoundRobin,
+}
+
+impl SchedPolicy {
+  fn as_raw(self) -> libc::c_int {
+    match self {
+      SchedPolicy::Fifo => libc::SCHED_FIFO,
+      SchedPolicy::RoundRobin => libc::SCHED_RR,
+    }
+  }
+}
+
+/// Real-time scheduling for the forwarding thread.
+///
+/// A real-time thread preempts every normal thread on its CPU for as long as
+/// it is runnable.  The forwarding loop blocks in `epoll_wait` when there is
+/// no traffic, but under sustained load it can starve other threads on the
+/// same CPU, including the Axl tunnel thread, so keep the priority modest and
+/// leave the kernel's RT throttling enabled.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize))]
+pub struct RtSched {
+  pub policy: SchedPolicy,
+  /// 1 (lowest) to 99 (highest).
+  pub priority: i32,
+}
+
+/// Apply `sched` to the calling thread.
+pub fn set_current_thread(sched: &RtSched) -> Result<(), String> {
+  let param = libc::sched_param {
+    sched_priority: sched.priority,
+  };
+  let rc = unsafe { libc::pthread_setschedparam(libc::pthread_self(), sched.policy.as_raw(), &param) };
+  match rc {
+    0 => Ok(()),
+    libc::EPERM => Err(format!(
+      "Not permitted to use {:?} scheduling at priority {}: needs CAP_SYS_NICE or a sufficient RLIMIT_RTPRIO",
+      sched.policy, sched.priority
+    )),
+    rc => Err(format!(
+      "Failed to set {:?} scheduling at priority {}: {}",
+      sched.policy,
+      sched.priority,
+      io::Error::from_raw_os_error(rc)
+    )),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{set_current_thread, RtSched, SchedPolicy};
+  use std::io;
+
+  /// Scheduling policy and priority of the calling thread, as raw values.
+  fn current_thread() -> io::Result<(libc::c_int, i32)> {
+    let mut policy = 0;
+    let mut param = libc::sched_param { sched_priority: 0 };
+    let rc = unsafe { libc::pthread_getschedparam(libc::pthread_self(), &mut policy, &mut param) };
+    if rc != 0 {
+      return Err(io::Error::from_raw_os_error(rc));
+    }
+    Ok((policy, param.sched_priority))
+  }
+
+  #[test]
+  fn sets_and_reads_back_policy() {
+    // Use a thread of our own so the test runner's threads keep their policy.
+    std::thread::spawn(|| {
+      let sched = RtSched {
+        policy: SchedPolicy::RoundRobin,
+        priority: 1,
+      };
+      if let Err(e) = set_current_thread(&sched) {
+        // Needs CAP_SYS_NICE, which test environments usually lack.
+        assert!(e.contains("CAP_SYS_NICE"), "{e}");
+        return;
+      }
+      assert_eq!(current_thread().unwrap(), (libc::SCHED_RR, 1));
+    })
+    .join()
+    .unwrap();
+  }
+}
@@ -1,13 +1,83 @@
+use nix::errno::Errno;
 use nix::fcntl::{fcntl, FcntlArg, FdFlag};
-use std::os::unix::io::RawFd;
+use nix::sys::socket::{connect, getpeername, getsockopt, recv, setsockopt, sockopt, GetSockOpt, MsgFlags, SetSockOpt, UnixAddr};
+use std::ffi::OsString;
+use std::io;
+use std::os::unix::io::{AsFd, AsRawFd, RawFd};
+use std::os::unix::net::UnixDatagram;
+use std::path::Path;
 
 /// Set or clear the `FD_CLOEXEC` flag on a file descriptor
-pub fn set_cloexec(fd: RawFd, enable: bool) {
-    let flags = fcntl(fd, FcntlArg::F_GETFD).expect("fcntl failed"); // Get current flags
+pub fn set_cloexec(fd: RawFd, enable: bool) -> nix::Result<()> {
+    let flags = fcntl(fd, FcntlArg::F_GETFD)?; // Get current flags
     let new_flags = if enable {
         FdFlag::from_bits_truncate(flags) | FdFlag::FD_CLOEXEC // Set the CLOEXEC flag without affecting other ones
     } else {
         FdFlag::from_bits_truncate(flags) & !FdFlag::FD_CLOEXEC // Clear CLOEXEC flag preserving the other ones
     };
-    fcntl(fd, FcntlArg::F_SETFD(new_flags)).expect("Failed to set FD_CLOEXEC"); // Set modified flags
+    fcntl(fd, FcntlArg::F_SETFD(new_flags))?; // Set modified flags
+    Ok(())
+}
+
+/// Restrict a socket to one network interface with `SO_BINDTODEVICE`.
+/// May fail with `EPERM` without `CAP_NET_RAW`, and fails with `ENODEV` for an unknown
+/// interface.
+pub fn bind_to_device<F: AsFd>(sock: &F, device: &str) -> nix::Result<()> {
+    setsockopt(sock, sockopt::BindToDevice, &OsString::from(device))
+}
+
+/// Set a buffer size option like `SO_RCVBUF` and read back what the kernel
+/// applied.  Linux doubles the value for its bookkeeping and silently clamps
+/// it to `net.core.rmem_max` or `wmem_max`.
+pub fn set_buffer_size<F, O>(sock: &F, opt: O, size: usize) -> nix::Result<usize>
+where
+    F: AsFd,
+    O: SetSockOpt<Val = usize> + GetSockOpt<Val = usize> + Copy,
+{
+    setsockopt(sock, opt, &size)?;
+    getsockopt(sock, opt)
+}
+
+/// Connect a datagram socket to the Unix socket bound at `path`, unless it
+/// already has a peer.  Returns whether it connected.
+pub fn connect_unix_peer<F: AsRawFd + ?Sized>(sock: &F, path: &Path) -> nix::Result<bool> {
+    match getpeername::<UnixAddr>(sock.as_raw_fd()) {
+        Ok(_) => return Ok(false),
+        Err(Errno::ENOTCONN) => {}
+        Err(e) => return Err(e),
+    }
+    connect(sock.as_raw_fd(), &UnixAddr::new(path)?)?;
+    Ok(true)
+}
+
+/// Send a zero-length datagram across a connected socket pair in both
+/// directions and check that each one arrives
+pub fn probe_socket_pair(a: &UnixDatagram, b: &UnixDatagram) -> io::Result<()> {
+    for (tx, rx) in [(a, b), (b, a)] {
+        tx.send(&[])?;
+        let mut buf = [0u8; 1];
+        if recv(rx.as_raw_fd(), &mut buf, MsgFlags::MSG_DONTWAIT)? != 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "probe came back non-empty"));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::set_buffer_size;
+    use nix::sys::socket::sockopt;
+    use std::os::unix::net::UnixDatagram;
+
+    #[test]
+    fn reads_back_buffer_sizes() {
+        let (sock, _peer) = UnixDatagram::pair().unwrap();
+        // Well below any net.core.*mem_max, so applied as asked, and reported
+        // doubled.
+        let rcv = set_buffer_size(&sock, sockopt::RcvBuf, 32768).unwrap();
+        let snd = set_buffer_size(&sock, sockopt::SndBuf, 32768).unwrap();
+        for applied in [rcv, snd] {
+            assert!((32768..=65536).contains(&applied), "{applied}");
+        }
+    }
 }
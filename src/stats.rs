@@ -0,0 +1,331 @@
+use std::ops::AddAssign;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+macro_rules! forward_stats {
+  ($($(#[$doc:meta])* $name:ident,)*) => {
+    /// Counters maintained by the forwarding loop.  They are updated with
+    /// relaxed atomics so another thread can read them while the loop runs.
+    #[derive(Debug, Default)]
+    pub struct ForwardStats {
+      $($(#[$doc])* pub $name: AtomicU64,)*
+      /// Traffic counters by local socket index.  The loop takes the slice
+      /// once at startup and counts without the lock.
+      pairs: Mutex<Arc<[PairStats]>>,
+    }
+
+    /// Point-in-time copy of [`ForwardStats`].
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    pub struct StatsSnapshot {
+      $($(#[$doc])* pub $name: u64,)*
+    }
+
+    impl ForwardStats {
+      pub fn snapshot(&self) -> StatsSnapshot {
+        StatsSnapshot {
+          $($name: self.$name.load(Ordering::Relaxed),)*
+        }
+      }
+    }
+
+    impl AddAssign<&StatsSnapshot> for StatsSnapshot {
+      fn add_assign(&mut self, other: &StatsSnapshot) {
+        $(self.$name += other.$name;)*
+      }
+    }
+
+    impl StatsSnapshot {
+      /// Counter names in the order used by the binary layout.
+      pub const FIELDS: &'static [&'static str] = &[$(stringify!($name),)*];
+
+      /// Every counter with its name, in [`Self::FIELDS`] order.
+      pub fn counters(&self) -> Vec<(&'static str, u64)> {
+        vec![$((stringify!($name), self.$name),)*]
+      }
+
+      /// Serialize into the compact binary layout:
+      ///
+      /// | offset  | size | content                                  |
+      /// |---------|------|------------------------------------------|
+      /// | 0       | 4    | magic `b"TIST"`                          |
+      /// | 4       | 2    | layout version, `u16` little endian      |
+      /// | 6       | 2    | number of counters `n`, `u16` LE         |
+      /// | 8       | 8·n  | counters as `u64` LE, in [`Self::FIELDS`] order |
+      ///
+      /// New counters are only ever appended, so a reader can consume a
+      /// snapshot from a newer writer by ignoring the trailing counters.
+      pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(8 + 8 * Self::FIELDS.len());
+        out.extend_from_slice(STATS_MAGIC);
+        out.extend_from_slice(&STATS_VERSION.to_le_bytes());
+        out.extend_from_slice(&(Self::FIELDS.len() as u16).to_le_bytes());
+        $(out.extend_from_slice(&self.$name.to_le_bytes());)*
+        out
+      }
+
+      /// Parse the layout written by [`StatsSnapshot::to_bytes`].  Counters
+      /// missing from an older writer read as zero.
+      pub fn from_bytes(buf: &[u8]) -> Result<Self, String> {
+        if buf.len() < 8 || &buf[..4] != STATS_MAGIC {
+          return Err("Not a binary stats snapshot".to_string());
+        }
+        let version = u16::from_le_bytes([buf[4], buf[5]]);
+        if version != STATS_VERSION {
+          return Err(format!("Unsupported stats layout version {version}"));
+        }
+        let n = usize::from(u16::from_le_bytes([buf[6], buf[7]]));
+        let body = &buf[8..];
+        if body.len() != 8 * n {
+          return Err(format!(
+            "Stats snapshot holds {} bytes for {n} counters",
+            body.len()
+          ));
+        }
+        let mut vals = body
+          .chunks_exact(8)
+          .map(|c| u64::from_le_bytes(c.try_into().unwrap()));
+        Ok(StatsSnapshot {
+          $($name: vals.next().unwrap_or(0),)*
+        })
+      }
+    }
+  };
+}
+
+macro_rules! pair_stats {
+  ($($(#[$doc:meta])* $name:ident,)*) => {
+    /// Traffic counters of a single port pair.
+    #[derive(Debug, Default)]
+    pub struct PairStats {
+      $($(#[$doc])* pub $name: AtomicU64,)*
+    }
+
+    /// Point-in-time copy of [`PairStats`].
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    pub struct PairSnapshot {
+      $($(#[$doc])* pub $name: u64,)*
+    }
+
+    impl PairSnapshot {
+      /// Every counter with its name.
+      pub fn counters(&self) -> Vec<(&'static str, u64)> {
+        vec![$((stringify!($name), self.$name),)*]
+      }
+    }
+
+    impl PairStats {
+      pub fn snapshot(&self) -> PairSnapshot {
+        PairSnapshot {
+          $($name: self.$name.load(Ordering::Relaxed),)*
+        }
+      }
+
+      fn from_snapshot(snap: PairSnapshot) -> Self {
+        PairStats {
+          $($name: AtomicU64::new(snap.$name),)*
+        }
+      }
+    }
+  };
+}
+
+pair_stats! {
+  /// Datagrams read from the local socket.
+  packets_to_outside,
+  /// Payload bytes of `packets_to_outside`.
+  bytes_to_outside,
+  /// Datagrams delivered to the local socket.
+  packets_from_outside,
+  /// Payload bytes of `packets_from_outside`.
+  bytes_from_outside,
+  /// Packets from the local socket which the outside socket did not take.
+  drops_outside,
+  /// Datagrams for the local socket which it did not take.
+  drops_inside,
+  /// Datagrams from the local socket dropped by the rate limit.
+  rate_limit_drops,
+  /// Numbered frames from the outside, with a sequence window set.  Not
+  /// duplicates, nor frames which showed up after being counted as lost.
+  frames_received,
+  /// Numbered frames from the outside which did not show up within the
+  /// sequence window.
+  frames_lost,
+}
+
+impl PairSnapshot {
+  /// Share of the numbered frames from the outside which were lost, none
+  /// before any were expected.
+  pub fn loss_rate(&self) -> Option<f64> {
+    let expected = self.frames_received + self.frames_lost;
+    (expected > 0).then(|| self.frames_lost as f64 / expected as f64)
+  }
+}
+
+impl ForwardStats {
+  /// Counters of local sockets `0..n`, kept across calls.
+  pub(crate) fn pairs(&self, n: usize) -> Arc<[PairStats]> {
+    let mut pairs = self.pairs.lock().unwrap();
+    if pairs.len() < n {
+      *pairs = (0..n)
+        .map(|j| pairs.get(j).map(PairStats::snapshot).unwrap_or_default())
+        .map(PairStats::from_snapshot)
+        .collect();
+    }
+    pairs.clone()
+  }
+
+  /// Traffic of each port pair, indexed like the local sockets.  Empty until
+  /// the forwarding loop started.
+  pub fn pair_snapshot(&self) -> Vec<PairSnapshot> {
+    self.pairs.lock().unwrap().iter().map(PairStats::snapshot).collect()
+  }
+}
+
+/// Stats of several [`TunnelInserter`](crate::TunnelInserter)s in one
+/// process, each under its own label.  Every instance keeps counting into its
+/// own [`ForwardStats`], so the instances do not contend with each other; the
+/// registry sums them up when asked.
+#[derive(Debug, Default)]
+pub struct StatsRegistry {
+  instances: Mutex<Vec<(String, Arc<ForwardStats>)>>,
+}
+
+impl StatsRegistry {
+  /// Fresh counters for the instance called `label`, to be handed to
+  /// [`TunnelInserter::with_stats`](crate::TunnelInserter::with_stats).
+  pub fn register(&self, label: &str) -> Arc<ForwardStats> {
+    let stats = Arc::new(ForwardStats::default());
+    self.instances.lock().unwrap().push((label.to_string(), stats.clone()));
+    stats
+  }
+
+  /// Snapshot of every registered instance, in registration order.
+  pub fn by_instance(&self) -> Vec<(String, StatsSnapshot)> {
+    let instances = self.instances.lock().unwrap();
+    instances.iter().map(|(l, s)| (l.clone(), s.snapshot())).collect()
+  }
+
+  /// Sum of all registered instances.
+  pub fn total(&self) -> StatsSnapshot {
+    let mut total = StatsSnapshot::default();
+    for (_, snap) in self.by_instance() {
+      total += &snap;
+    }
+    total
+  }
+}
+
+const STATS_MAGIC: &[u8; 4] = b"TIST";
+const STATS_VERSION: u16 = 1;
+
+forward_stats! {
+  /// Inbound packets sent straight back to the outside in echo mode.
+  echoes,
+  /// `epoll_wait` calls which timed out, or were interrupted by a signal,
+  /// with no descriptor ready.
+  idle_wakeups,
+  /// Coalesced frames sent to the outside.
+  frames_sent,
+  /// Inbound payloads which could not be split as frames.
+  bad_frames,
+  /// Times the loop stopped because `epoll_wait` kept waking up without data.
+  spin_aborts,
+  /// Inbound packets dropped because their flow id was unknown or did not
+  /// fit in the payload.
+  flow_id_drops,
+  /// Inbound packets dropped because their payload was empty.
+  empty_drops,
+  /// Inbound packets dropped because a port was outside the allowed ranges.
+  port_range_drops,
+  /// Inbound packets dropped because they came from the wrong source IP.
+  src_ip_mismatches,
+  /// Times a burst of source IP mismatches reached the spoof guard threshold.
+  spoof_alarms,
+  /// Numbered inbound frames that never arrived, see
+  /// [`PairSnapshot::frames_lost`] and [`PairSnapshot::loss_rate`] for the
+  /// split by port pair.
+  frames_lost,
+  /// Inbound packets dropped because their TTL was below the minimum.
+  low_ttl_drops,
+  /// Failed `recvmmsg` calls, other than for lack of data, and errors read
+  /// off a faulted UDP outside socket.
+  recv_errors,
+  /// Datagrams dropped because they did not fit into a receive buffer.
+  oversize_drops,
+  /// Descriptors which `epoll_wait` reported in an error state.
+  fd_faults,
+  /// Inbound packets dropped because they were addressed to another IP.
+  dst_ip_mismatches,
+  /// Inbound IPv4 datagrams put back together from fragments.
+  reassembled,
+  /// Inbound IPv4 fragments dropped as malformed, overlapping, or for lack
+  /// of buffer space.
+  fragment_drops,
+  /// Fragmented inbound datagrams given up on because the rest did not
+  /// arrive in time.
+  fragment_timeouts,
+  /// Flushes to the outside which found the socket full, leaving packets in
+  /// the backlog or dropping them.
+  outside_full,
+  /// Counter dumps asked for on the control pipe.
+  stats_dumps,
+  /// Datagrams from the local sockets dropped by the rate limit, see the
+  /// port pairs for the split.
+  rate_limit_drops,
+  /// Inbound packets rejected as shorter than their headers.
+  short_packets,
+  /// Inbound packets rejected for a malformed link layer or IP header, other
+  /// than its options.
+  bad_headers,
+  /// Inbound packets rejected because an IP or UDP length did not match.
+  bad_lengths,
+  /// Inbound packets rejected for the reserved IPv4 flag bit.
+  reserved_flag_drops,
+  /// Inbound packets rejected because they were not UDP.
+  not_udp_drops,
+  /// Inbound packets rejected for a wrong IPv4 header checksum.
+  bad_ip_checksums,
+  /// Inbound packets rejected for a wrong or missing UDP checksum.
+  bad_udp_checksums,
+  /// Inbound packets rejected for malformed IPv4 options.
+  bad_ip_options,
+}
+
+/// Increment a counter by one.
+pub fn bump(counter: &AtomicU64) {
+  counter.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Increment a counter by `n`.
+pub fn bump_by(counter: &AtomicU64, n: u64) {
+  counter.fetch_add(n, Ordering::Relaxed);
+}
+
+#[cfg(test)]
+mod tests {
+  use super::StatsSnapshot;
+
+  #[test]
+  fn binary_round_trip() {
+    let n = StatsSnapshot::FIELDS.len();
+    let mut bytes = b"TIST".to_vec();
+    bytes.extend_from_slice(&1u16.to_le_bytes());
+    bytes.extend_from_slice(&(n as u16).to_le_bytes());
+    for j in 0..n as u64 {
+      bytes.extend_from_slice(&(u64::MAX - j).to_le_bytes());
+    }
+    let snap = StatsSnapshot::from_bytes(&bytes).unwrap();
+    assert_eq!(snap.echoes, u64::MAX);
+    assert_eq!(snap.to_bytes(), bytes);
+    assert_eq!(StatsSnapshot::from_bytes(&snap.to_bytes()), Ok(snap));
+  }
+
+  #[test]
+  fn binary_rejects_garbage() {
+    let mut bytes = StatsSnapshot::default().to_bytes();
+    assert!(StatsSnapshot::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+    bytes[4] = 99;
+    assert!(StatsSnapshot::from_bytes(&bytes).is_err());
+    assert!(StatsSnapshot::from_bytes(b"nope").is_err());
+  }
+}
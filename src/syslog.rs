@@ -0,0 +1,261 @@
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::os::unix::net::UnixDatagram;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Where [`SyslogLogger`] sends its messages.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SyslogTarget {
+  /// The local syslog daemon's datagram socket, usually `/dev/log`.
+  Local(PathBuf),
+  /// A remote syslog server over UDP (RFC 5426).
+  Remote(SocketAddr),
+}
+
+impl std::str::FromStr for SyslogTarget {
+  type Err = String;
+
+  /// A path starting with `/`, or `HOST:PORT` with a literal address.
+  fn from_str(s: &str) -> Result<Self, String> {
+    if s.starts_with('/') {
+      return Ok(SyslogTarget::Local(PathBuf::from(s)));
+    }
+    s.parse()
+      .map(SyslogTarget::Remote)
+      .map_err(|_| format!("expected a socket path or IP:PORT, got {s:?}"))
+  }
+}
+
+/// Facility code of a syslog facility name like `daemon` or `local3`.
+pub fn facility_code(name: &str) -> Option<u8> {
+  let code = match name {
+    "kern" => 0,
+    "user" => 1,
+    "daemon" => 3,
+    "auth" => 4,
+    "syslog" => 5,
+    _ => {
+      let n: u8 = name.strip_prefix("local")?.parse().ok()?;
+      return (n < 8).then_some(16 + n);
+    }
+  };
+  Some(code)
+}
+
+enum Sink {
+  Local(UnixDatagram),
+  Remote(UdpSocket),
+}
+
+/// Sends log records as RFC 5424 syslog messages, one datagram each.
+/// Messages that can't be sent are lost, logging never blocks or fails.
+pub struct SyslogLogger {
+  sink: Sink,
+  facility: u8,
+  level: LevelFilter,
+  hostname: String,
+}
+
+impl SyslogLogger {
+  /// A logger for records up to `level`, sent to `target` with `facility`,
+  /// see [`facility_code`].
+  pub fn new(target: &SyslogTarget, facility: u8, level: LevelFilter) -> io::Result<Self> {
+    let sink = match target {
+      SyslogTarget::Local(path) => {
+        let sock = UnixDatagram::unbound()?;
+        sock.connect(path)?;
+        sock.set_nonblocking(true)?;
+        Sink::Local(sock)
+      }
+      SyslogTarget::Remote(addr) => {
+        let bind: SocketAddr = match addr {
+          SocketAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
+          SocketAddr::V6(_) => ([0u16; 8], 0).into(),
+        };
+        let sock = UdpSocket::bind(bind)?;
+        sock.connect(addr)?;
+        sock.set_nonblocking(true)?;
+        Sink::Remote(sock)
+      }
+    };
+    Ok(Self {
+      sink,
+      facility,
+      level,
+      hostname: hostname().unwrap_or_else(|| "-".to_string()),
+    })
+  }
+
+  /// The RFC 5424 message for `record`: priority, version, timestamp,
+  /// hostname, app name, process id, no message id or structured data.
+  fn format(&self, record: &Record) -> String {
+    format!(
+      "<{}>1 {} {} tunnel_inserter {} {} - {}",
+      self.facility * 8 + severity(record.level()),
+      timestamp(SystemTime::now()),
+      self.hostname,
+      std::process::id(),
+      record.target().split("::").next().unwrap_or("-"),
+      record.args()
+    )
+  }
+}
+
+impl Log for SyslogLogger {
+  fn enabled(&self, metadata: &Metadata) -> bool {
+    metadata.level() <= self.level
+  }
+
+  fn log(&self, record: &Record) {
+    if !self.enabled(record.metadata()) {
+      return;
+    }
+    let msg = self.format(record);
+    // A daemon that doesn't keep up loses messages, WouldBlock included,
+    // rather than stalling the forwarding loop.
+    let _ = match &self.sink {
+      Sink::Local(sock) => sock.send(msg.as_bytes()),
+      Sink::Remote(sock) => sock.send(msg.as_bytes()),
+    };
+  }
+
+  fn flush(&self) {}
+}
+
+/// Syslog severity of a log level.
+fn severity(level: Level) -> u8 {
+  match level {
+    Level::Error => 3,
+    Level::Warn => 4,
+    Level::Info => 6,
+    Level::Debug | Level::Trace => 7,
+  }
+}
+
+/// `t` as an RFC 3339 UTC timestamp with milliseconds.
+fn timestamp(t: SystemTime) -> String {
+  let since = t.duration_since(UNIX_EPOCH).unwrap_or_default();
+  let secs = since.as_secs();
+  let (days, rem) = ((secs / 86400) as i64, secs % 86400);
+  // Civil date from days since the epoch (Howard Hinnant's algorithm).
+  let z = days + 719468;
+  let era = z.div_euclid(146097);
+  let doe = z.rem_euclid(146097);
+  let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+  let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+  let mp = (5 * doy + 2) / 153;
+  let day = doy - (153 * mp + 2) / 5 + 1;
+  let month = if mp < 10 { mp + 3 } else { mp - 9 };
+  let year = yoe + era * 400 + i64::from(month <= 2);
+  format!(
+    "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:03}Z",
+    rem / 3600,
+    rem / 60 % 60,
+    rem % 60,
+    since.subsec_millis()
+  )
+}
+
+fn hostname() -> Option<String> {
+  let mut buf = [0u8; 256];
+  // SAFETY: the buffer outlives the call, which writes at most its length.
+  if unsafe { libc::gethostname(buf.as_mut_ptr().cast(), buf.len()) } != 0 {
+    return None;
+  }
+  let len = buf.iter().position(|&b| b == 0)?;
+  // RFC 5424 hostnames are printable ASCII without spaces.
+  let name = std::str::from_utf8(&buf[..len]).ok()?;
+  (!name.is_empty() && name.bytes().all(|b| b.is_ascii_graphic())).then(|| name.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{facility_code, timestamp, SyslogLogger, SyslogTarget};
+  use log::{Level, LevelFilter, Log, Record};
+  use std::net::UdpSocket;
+  use std::os::unix::net::UnixDatagram;
+  use std::time::{Duration, UNIX_EPOCH};
+
+  fn record(level: Level, f: impl FnOnce(&Record)) {
+    f(&Record::builder()
+      .args(format_args!("Port pair fd0 idle"))
+      .level(level)
+      .target("tunnel_inserter::forward")
+      .build());
+  }
+
+  #[test]
+  fn sends_rfc5424_messages() {
+    let path = std::env::temp_dir().join(format!("syslog_{}", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let server = UnixDatagram::bind(&path).unwrap();
+    server.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let target: SyslogTarget = path.to_str().unwrap().parse().unwrap();
+    let logger = SyslogLogger::new(&target, facility_code("daemon").unwrap(), LevelFilter::Info).unwrap();
+
+    record(Level::Warn, |r| logger.log(r));
+    // Filtered out.
+    record(Level::Debug, |r| logger.log(r));
+    record(Level::Info, |r| logger.log(r));
+    let mut buf = [0u8; 512];
+    let mut recv = || {
+      let n = server.recv(&mut buf).unwrap();
+      String::from_utf8(buf[..n].to_vec()).unwrap()
+    };
+    let msg = recv();
+    let fields: Vec<&str> = msg.splitn(8, ' ').collect();
+    // daemon (3) * 8 + warning (4)
+    assert_eq!(fields[0], "<28>1");
+    assert_eq!(fields[1].len(), "2024-01-01T00:00:00.000Z".len(), "{msg}");
+    assert!(fields[1].ends_with('Z'), "{msg}");
+    assert!(!fields[2].is_empty() && !fields[2].contains(' '));
+    assert_eq!(fields[3], "tunnel_inserter");
+    assert_eq!(fields[4], std::process::id().to_string());
+    assert_eq!(&fields[5..], ["tunnel_inserter", "-", "Port pair fd0 idle"]);
+    assert!(recv().starts_with("<30>1 "));
+    std::fs::remove_file(&path).unwrap();
+  }
+
+  #[test]
+  fn drops_messages_when_the_daemon_lags() {
+    let path = std::env::temp_dir().join(format!("syslog_full_{}", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    // Never read, so its queue fills up.
+    let server = UnixDatagram::bind(&path).unwrap();
+    let target = SyslogTarget::Local(path.clone());
+    let logger = SyslogLogger::new(&target, facility_code("daemon").unwrap(), LevelFilter::Info).unwrap();
+    for _ in 0..10_000 {
+      record(Level::Info, |r| logger.log(r));
+    }
+    server.set_nonblocking(true).unwrap();
+    let mut buf = [0u8; 512];
+    let queued = std::iter::from_fn(|| server.recv(&mut buf).ok()).count();
+    assert!(0 < queued && queued < 10_000, "{queued}");
+    std::fs::remove_file(&path).unwrap();
+  }
+
+  #[test]
+  fn sends_to_remote_servers() {
+    let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+    server.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let target = server.local_addr().unwrap().to_string().parse().unwrap();
+    let logger = SyslogLogger::new(&target, facility_code("local0").unwrap(), LevelFilter::Info).unwrap();
+    record(Level::Error, |r| logger.log(r));
+    let mut buf = [0u8; 512];
+    let n = server.recv(&mut buf).unwrap();
+    // local0 (16) * 8 + error (3)
+    assert!(buf[..n].starts_with(b"<131>1 "));
+  }
+
+  #[test]
+  fn formats_timestamps_and_names() {
+    assert_eq!(timestamp(UNIX_EPOCH), "1970-01-01T00:00:00.000Z");
+    let t = UNIX_EPOCH + Duration::from_millis(1_709_210_096_789);
+    assert_eq!(timestamp(t), "2024-02-29T12:34:56.789Z");
+    assert_eq!(facility_code("local7"), Some(23));
+    assert_eq!((facility_code("local8"), facility_code("mail7")), (None, None));
+    assert!("example.org:514".parse::<SyslogTarget>().is_err());
+  }
+}
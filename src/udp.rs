@@ -1,15 +1,24 @@
 #![allow(dead_code)]
 
+use log::debug;
 use std::convert::TryFrom;
-use std::net::Ipv4Addr;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 
 const IPV4_HEADER_LEN: usize = 20;
+const IPV6_HEADER_LEN: usize = 40;
 const UDP_HEADER_LEN: usize = 8;
+const ETHERNET_HEADER_LEN: usize = 14;
+const VLAN_TAG_LEN: usize = 4;
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_VLAN: u16 = 0x8100;
 
-/// Compute one's complement checksum for a given buffer
-pub fn checksum(mut data: &[u8]) -> u16 {
-    let mut sum: u32 = 0;
+/// Longest IP and UDP headers in front of the payload of a packet built
+/// here: an IPv4 header with 40 bytes of options.
+pub const MAX_HEADERS_LEN: usize = 60 + UDP_HEADER_LEN;
 
+/// Add the 16-bit words of `data` to `sum`, an odd final byte padded with
+/// zero.
+fn sum_words(mut sum: u32, mut data: &[u8]) -> u32 {
     while data.len() >= 2 {
         sum += u32::from(u16::from_be_bytes([data[0], data[1]]));
         data = &data[2..];
@@ -18,6 +27,28 @@ pub fn checksum(mut data: &[u8]) -> u16 {
     if !data.is_empty() {
         sum += u32::from(data[0]) << 8;
     }
+    sum
+}
+
+/// Fold the carries of `sum` back in and complement the result.
+fn fold_checksum(mut sum: u32) -> u16 {
+    while (sum >> 16) != 0 {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+
+    !u16::try_from(sum).expect("checksum overflow")
+}
+
+/// Compute one's complement checksum for a given buffer
+pub fn checksum(data: &[u8]) -> u16 {
+    fold_checksum(sum_words(0, data))
+}
+
+/// Update a checksum for one 16-bit word of the covered data changing from
+/// `old_word` to `new_word`, without summing everything again (RFC 1624,
+/// eqn. 3).
+pub fn checksum_update(old_sum: u16, old_word: u16, new_word: u16) -> u16 {
+    let mut sum = u32::from(!old_sum) + u32::from(!old_word) + u32::from(new_word);
 
     while (sum >> 16) != 0 {
         sum = (sum & 0xFFFF) + (sum >> 16);
@@ -26,6 +57,128 @@ pub fn checksum(mut data: &[u8]) -> u16 {
     !u16::try_from(sum).expect("checksum overflow")
 }
 
+/// Overwrite the UDP payload of an IPv4 or IPv6 `packet` with `new_bytes`
+/// from `offset` on, and update the UDP checksum in place to match.  The IP
+/// header checksum does not cover the payload and stays valid as is, and an
+/// IPv4 packet sent without UDP checksum keeps going without one.  Panics if
+/// `packet` is neither IPv4 nor IPv6 or the new bytes run past its end.
+pub fn patch_udp_payload(packet: &mut [u8], offset: usize, new_bytes: &[u8]) {
+    let udp_offset = match packet[0] >> 4 {
+        4 => usize::from(packet[0] & 0x0F) * 4,
+        6 => IPV6_HEADER_LEN,
+        v => panic!("Not an IP packet (version = {v})"),
+    };
+    let start = udp_offset + UDP_HEADER_LEN + offset;
+    let end = start + new_bytes.len();
+    assert!(end <= packet.len(), "Patch runs past the end of the packet");
+    let old_sum = u16::from_be_bytes([packet[udp_offset + 6], packet[udp_offset + 7]]);
+    if old_sum == 0 {
+        packet[start..end].copy_from_slice(new_bytes);
+        return;
+    }
+
+    // The checksum covers 16-bit words counted from the start of the UDP
+    // header, an odd final byte padded with zero.
+    let word_at = |packet: &[u8], at: usize| {
+        u16::from_be_bytes([packet[at], packet.get(at + 1).copied().unwrap_or(0)])
+    };
+    let first = start - (start - udp_offset) % 2;
+    let old_words: Vec<u16> = (first..end).step_by(2).map(|at| word_at(packet, at)).collect();
+    packet[start..end].copy_from_slice(new_bytes);
+    let mut sum = old_sum;
+    for (at, old_word) in (first..end).step_by(2).zip(old_words) {
+        sum = checksum_update(sum, old_word, word_at(packet, at));
+    }
+    // Zero means no checksum (RFC 768).
+    let sum = if sum == 0 { 0xFFFF } else { sum };
+    packet[udp_offset + 6..udp_offset + 8].copy_from_slice(&sum.to_be_bytes());
+}
+
+/// Which checksums [`encode_ipv4_udp_header_with`] computes.  Left out ones
+/// stay zero, for a NIC with checksum offload to fill in.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize), serde(rename_all = "snake_case"))]
+pub enum ChecksumMode {
+    /// The IPv4 header checksum, and the UDP checksum if asked for
+    #[default]
+    Full,
+    /// Only the UDP checksum if asked for, the IPv4 header checksum is left
+    /// zero for the NIC
+    IpOnly,
+    /// Neither, both are left zero, even with [`Ipv4Options::udp_checksum`]
+    None,
+}
+
+/// Caller-chosen header fields of packets built by [`create_ipv4_udp_packet_with`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Ipv4Options {
+    /// Identification field
+    pub identification: u16,
+    /// Time to live
+    pub ttl: u8,
+    /// Differentiated services code point, the upper six bits of the TOS byte
+    pub dscp: u8,
+    /// ECN codepoint, the lower two bits of the TOS byte
+    pub ecn: u8,
+    /// Set the don't fragment flag
+    pub dont_fragment: bool,
+    /// Raw IPv4 options placed after the fixed header, padded by the caller
+    /// to a multiple of 4 bytes and at most 40 bytes long
+    pub options: Vec<u8>,
+    /// Fill in the UDP checksum instead of leaving it zero ("not computed")
+    pub udp_checksum: bool,
+    /// Which checksums to compute, the rest is left to the hardware
+    pub checksum: ChecksumMode,
+    /// Take DSCP and ECN from the payload instead if it is an IPv4 packet,
+    /// for QoS transparency when tunneling IP in UDP
+    pub copy_inner_tos: bool,
+}
+
+impl Default for Ipv4Options {
+    fn default() -> Self {
+        Self {
+            identification: 0,
+            ttl: 64,
+            dscp: 0,
+            ecn: 0,
+            dont_fragment: true,
+            options: Vec::new(),
+            udp_checksum: false,
+            checksum: ChecksumMode::Full,
+            copy_inner_tos: false,
+        }
+    }
+}
+
+/// One's complement checksum of a UDP segment (header and payload) over the
+/// IPv4 pseudo-header.  The segment's own checksum field is included as is.
+fn udp_pseudo_checksum(src_ip: Ipv4Addr, dst_ip: Ipv4Addr, segment: &[u8]) -> u16 {
+    udp_pseudo_checksum_split(src_ip, dst_ip, segment, &[])
+}
+
+/// [`udp_pseudo_checksum`] of the segment `head` followed by `rest`, with
+/// `head` of even length.
+fn udp_pseudo_checksum_split(src_ip: Ipv4Addr, dst_ip: Ipv4Addr, head: &[u8], rest: &[u8]) -> u16 {
+    let mut sum = sum_words(0, &src_ip.octets());
+    sum = sum_words(sum, &dst_ip.octets());
+    sum = sum_words(sum, &[0, 17]); // Zero byte + protocol (UDP)
+    sum = sum_words(
+        sum,
+        &u16::try_from(head.len() + rest.len())
+            .expect("UDP segment too long")
+            .to_be_bytes(),
+    );
+    fold_checksum(sum_words(sum_words(sum, head), rest))
+}
+
+/// Whether the checksum of a UDP `segment` (header and payload) matches its
+/// contents.  Zero means the sender did not compute one, which IPv4 allows.
+fn udp_checksum_valid(src_ip: Ipv4Addr, dst_ip: Ipv4Addr, segment: &[u8]) -> bool {
+    let sent = u16::from_be_bytes([segment[6], segment[7]]);
+    // Summed over a segment carrying its correct checksum, the result is 0.
+    sent == 0 || udp_pseudo_checksum(src_ip, dst_ip, segment) == 0
+}
+
 /// Creates a valid IPv4 UDP packet
 pub fn create_ipv4_udp_packet(
     payload: &[u8],
@@ -34,93 +187,376 @@ pub fn create_ipv4_udp_packet(
     src_port: u16,
     dst_port: u16,
 ) -> Vec<u8> {
-    let udp_length = UDP_HEADER_LEN + payload.len();
-    let total_length = IPV4_HEADER_LEN + udp_length;
+    create_ipv4_udp_packet_with(
+        payload,
+        src_ip,
+        dst_ip,
+        src_port,
+        dst_port,
+        &Ipv4Options::default(),
+    )
+}
 
-    let mut packet = vec![0u8; total_length];
+/// Creates a valid IPv4 UDP packet with the given header options
+pub fn create_ipv4_udp_packet_with(
+    payload: &[u8],
+    src_ip: Ipv4Addr,
+    dst_ip: Ipv4Addr,
+    src_port: u16,
+    dst_port: u16,
+    opts: &Ipv4Options,
+) -> Vec<u8> {
+    let mut packet = vec![0u8; IPV4_HEADER_LEN + opts.options.len() + UDP_HEADER_LEN + payload.len()];
+    encode_ipv4_udp_into_with(&mut packet, payload, src_ip, dst_ip, src_port, dst_port, opts)
+        .expect("IPv4 packet too long");
+    packet
+}
+
+/// Why a packet could not be written by [`encode_ipv4_udp_into`] and friends.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EncodeError {
+    /// The buffer holds fewer than the `needed` bytes of the packet.
+    BufferTooSmall { needed: usize },
+    /// The packet exceeds the maximum length of an IP packet or UDP segment.
+    TooLong,
+}
+
+impl std::fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EncodeError::BufferTooSmall { needed } => write!(f, "buffer too small, need {needed} bytes"),
+            EncodeError::TooLong => write!(f, "packet too long"),
+        }
+    }
+}
+
+impl std::error::Error for EncodeError {}
+
+/// Like [`create_ipv4_udp_packet`], but writes the packet to the start of
+/// `dst` instead of allocating it.  Returns the length of the packet.
+pub fn encode_ipv4_udp_into(
+    dst: &mut [u8],
+    payload: &[u8],
+    src_ip: Ipv4Addr,
+    dst_ip: Ipv4Addr,
+    src_port: u16,
+    dst_port: u16,
+) -> Result<usize, EncodeError> {
+    encode_ipv4_udp_into_with(dst, payload, src_ip, dst_ip, src_port, dst_port, &Ipv4Options::default())
+}
+
+/// Like [`create_ipv4_udp_packet_with`], but writes the packet to the start
+/// of `dst` instead of allocating it.  Returns the length of the packet.
+pub fn encode_ipv4_udp_into_with(
+    dst: &mut [u8],
+    payload: &[u8],
+    src_ip: Ipv4Addr,
+    dst_ip: Ipv4Addr,
+    src_port: u16,
+    dst_port: u16,
+    opts: &Ipv4Options,
+) -> Result<usize, EncodeError> {
+    let needed = IPV4_HEADER_LEN + opts.options.len() + UDP_HEADER_LEN + payload.len();
+    let header_len = encode_ipv4_udp_header_with(dst, payload, src_ip, dst_ip, src_port, dst_port, opts)
+        .map_err(|e| match e {
+            EncodeError::BufferTooSmall { .. } => EncodeError::BufferTooSmall { needed },
+            e => e,
+        })?;
+    dst.get_mut(header_len..needed)
+        .ok_or(EncodeError::BufferTooSmall { needed })?
+        .copy_from_slice(payload);
+    Ok(needed)
+}
+
+/// Writes only the IPv4 and UDP headers of the packet carrying `payload` to
+/// the start of `dst`, checksums included, for sending them together with
+/// `payload` in one gather write.  Returns the length of the headers, at
+/// most [`MAX_HEADERS_LEN`].
+pub fn encode_ipv4_udp_header_with(
+    dst: &mut [u8],
+    payload: &[u8],
+    src_ip: Ipv4Addr,
+    dst_ip: Ipv4Addr,
+    src_port: u16,
+    dst_port: u16,
+    opts: &Ipv4Options,
+) -> Result<usize, EncodeError> {
+    assert!(
+        opts.options.len().is_multiple_of(4) && opts.options.len() <= 40,
+        "IPv4 options must be padded to a multiple of 4 bytes, at most 40"
+    );
+    assert!(opts.dscp < 64 && opts.ecn < 4, "DSCP or ECN out of range");
+    let ihl = IPV4_HEADER_LEN + opts.options.len();
+    let udp_length = UDP_HEADER_LEN + payload.len();
+    let total_length = ihl + udp_length;
+    let total_length_field = u16::try_from(total_length).map_err(|_| EncodeError::TooLong)?;
+    let header_len = ihl + UDP_HEADER_LEN;
+    let packet = dst
+        .get_mut(..header_len)
+        .ok_or(EncodeError::BufferTooSmall { needed: header_len })?;
 
     // IPv4 Header
-    packet[0] = 0x45; // Version (4) + IHL (5)
-    packet[1] = 0x00; // DSCP + ECN
-    packet[2..4].copy_from_slice(
-        &u16::try_from(total_length)
-            .expect("IPv4 packet too long")
-            .to_be_bytes(),
-    ); // Total length
-    packet[4..6].copy_from_slice(&0x0000u16.to_be_bytes()); // Identification
-    packet[6..8].copy_from_slice(&0x4000u16.to_be_bytes()); // Flags + Fragment offset
-    packet[8] = 64; // TTL
+    packet[0] = 0x40 | (ihl / 4) as u8; // Version (4) + IHL
+    // DSCP + ECN, of the inner packet if asked to and there is one
+    packet[1] = match opts.copy_inner_tos.then(|| inner_ipv4_tos(payload)).flatten() {
+        Some(tos) => tos,
+        None => opts.dscp << 2 | opts.ecn,
+    };
+    packet[2..4].copy_from_slice(&total_length_field.to_be_bytes()); // Total length
+    packet[4..6].copy_from_slice(&opts.identification.to_be_bytes()); // Identification
+    let flags: u16 = if opts.dont_fragment { 0x4000 } else { 0 };
+    packet[6..8].copy_from_slice(&flags.to_be_bytes()); // Flags + Fragment offset
+    packet[8] = opts.ttl; // TTL
     packet[9] = 17; // Protocol (UDP)
+    packet[10..12].copy_from_slice(&[0, 0]); // Checksum, filled in below
     packet[12..16].copy_from_slice(&src_ip.octets()); // Source IP
     packet[16..20].copy_from_slice(&dst_ip.octets()); // Destination IP
+    packet[IPV4_HEADER_LEN..ihl].copy_from_slice(&opts.options); // Options
 
-    // Compute IPv4 Header Checksum
-    let ip_checksum = checksum(&packet[..IPV4_HEADER_LEN]);
-    packet[10..12].copy_from_slice(&ip_checksum.to_be_bytes());
+    // Compute IPv4 Header Checksum, which covers the options as well
+    if opts.checksum == ChecksumMode::Full {
+        let ip_checksum = checksum(&packet[..ihl]);
+        packet[10..12].copy_from_slice(&ip_checksum.to_be_bytes());
+    }
 
     // UDP Header
-    let udp_offset = IPV4_HEADER_LEN;
+    let udp_offset = ihl;
     packet[udp_offset..udp_offset + 2].copy_from_slice(&src_port.to_be_bytes());
     packet[udp_offset + 2..udp_offset + 4].copy_from_slice(&dst_port.to_be_bytes());
-    packet[udp_offset + 4..udp_offset + 6].copy_from_slice(
-        &u16::try_from(udp_length)
-            .expect("UDP segment too long")
-            .to_be_bytes(),
-    );
+    packet[udp_offset + 4..udp_offset + 6].copy_from_slice(&(udp_length as u16).to_be_bytes());
+    packet[udp_offset + 6..udp_offset + 8].copy_from_slice(&[0, 0]);
 
-    // Copy Payload
-    let payload_offset = udp_offset + UDP_HEADER_LEN;
-    packet[payload_offset..].copy_from_slice(payload);
-
-    // Compute UDP Checksum (with pseudo-header)
-    if false {
-        let mut pseudo_header = Vec::new();
-        pseudo_header.extend_from_slice(&src_ip.octets());
-        pseudo_header.extend_from_slice(&dst_ip.octets());
-        pseudo_header.push(0); // Zero byte
-        pseudo_header.push(17); // Protocol (UDP)
-        pseudo_header.extend_from_slice(
-            &u16::try_from(udp_length)
-                .expect("UDP segment too long")
-                .to_be_bytes(),
-        );
-        pseudo_header
-            .extend_from_slice(&packet[udp_offset..udp_offset + UDP_HEADER_LEN + payload.len()]);
-
-        let udp_checksum = checksum(&pseudo_header);
+    // Compute UDP Checksum (with pseudo-header) over the header here and the
+    // payload wherever it is.  A computed zero goes out as 0xFFFF, since
+    // zero means no checksum (RFC 768).
+    if opts.udp_checksum && opts.checksum != ChecksumMode::None {
+        let udp_checksum = match udp_pseudo_checksum_split(src_ip, dst_ip, &packet[udp_offset..], payload) {
+            0 => 0xFFFF,
+            c => c,
+        };
         packet[udp_offset + 6..udp_offset + 8].copy_from_slice(&udp_checksum.to_be_bytes());
-    } else {
-        packet[udp_offset + 6..udp_offset + 8].copy_from_slice(&[0, 0]);
     }
 
-    packet
+    Ok(header_len)
+}
+
+/// The DSCP and ECN byte of `payload` if it is an IPv4 packet with a sound
+/// header, see [`Ipv4Options::copy_inner_tos`].
+fn inner_ipv4_tos(payload: &[u8]) -> Option<u8> {
+    let ihl = usize::from(payload.first()? & 0x0F) * 4;
+    if payload[0] >> 4 != 4 || ihl < IPV4_HEADER_LEN || payload.len() < ihl {
+        return None;
+    }
+    let total_length = usize::from(u16::from_be_bytes([payload[2], payload[3]]));
+    if total_length < ihl || total_length > payload.len() || checksum(&payload[..ihl]) != 0 {
+        return None;
+    }
+    Some(payload[1])
+}
+
+/// Walks the IPv4 options area (the header bytes after the fixed 20) and checks
+/// that every option's length stays inside it.
+fn ipv4_options_valid(mut opts: &[u8]) -> bool {
+    while let Some(&kind) = opts.first() {
+        match kind {
+            0 => return true,       // End of option list, the rest is padding
+            1 => opts = &opts[1..], // No operation
+            _ => {
+                let Some(&len) = opts.get(1) else {
+                    return false;
+                };
+                let len = usize::from(len);
+                if len < 2 || len > opts.len() {
+                    return false;
+                }
+                opts = &opts[len..];
+            }
+        }
+    }
+    true
+}
+
+/// What precedes the IPv4 header in a received buffer
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize), serde(rename_all = "snake_case"))]
+pub enum LinkLayer {
+    /// Nothing, the buffer starts with the IPv4 header
+    #[default]
+    RawIp,
+    /// An Ethernet II header
+    Ethernet,
+    /// An Ethernet II header with a single 802.1Q VLAN tag
+    EthernetVlan,
+}
+
+/// Strip the link layer header from `buf`, checking that it announces IPv4
+pub fn strip_link_layer(buf: &[u8], link_layer: LinkLayer) -> Option<&[u8]> {
+    let ethertype_at = match link_layer {
+        LinkLayer::RawIp => return Some(buf),
+        LinkLayer::Ethernet => ETHERNET_HEADER_LEN - 2,
+        LinkLayer::EthernetVlan => {
+            match buf.get(ETHERNET_HEADER_LEN - 2..ETHERNET_HEADER_LEN) {
+                Some(t) if u16::from_be_bytes([t[0], t[1]]) == ETHERTYPE_VLAN => {}
+                _ => {
+                    debug!("Frame is not 802.1Q tagged");
+                    return None;
+                }
+            }
+            ETHERNET_HEADER_LEN + VLAN_TAG_LEN - 2
+        }
+    };
+    match buf.get(ethertype_at..ethertype_at + 2) {
+        Some(t) if u16::from_be_bytes([t[0], t[1]]) == ETHERTYPE_IPV4 => Some(&buf[ethertype_at + 2..]),
+        Some(t) => {
+            debug!("Not an IPv4 frame (EtherType = {:#06x})", u16::from_be_bytes([t[0], t[1]]));
+            None
+        }
+        None => {
+            debug!("Frame too short for its link layer header");
+            None
+        }
+    }
+}
+
+/// Strictness knobs for [`parse_ipv4_udp_packet_with`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParseOptions {
+    /// Reject packets with the reserved ("evil") bit of the flags field set
+    pub reject_reserved_flag: bool,
+    /// Link layer header to strip before the IPv4 header
+    pub link_layer: LinkLayer,
+    /// Reject packets with a wrong UDP checksum.  When off, the UDP checksum
+    /// is not looked at, for checksum offload leaving it unfilled.  The IPv4
+    /// header checksum is always verified, whatever this says.
+    pub verify_checksums: bool,
+}
+
+impl Default for ParseOptions {
+    fn default() -> Self {
+        Self {
+            reject_reserved_flag: false,
+            link_layer: LinkLayer::default(),
+            verify_checksums: true,
+        }
+    }
+}
+
+/// Why a packet was rejected by [`parse_ipv4_udp_packet`] and friends.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ParseError {
+    /// Shorter than its IP and UDP headers.
+    TooShort,
+    /// Link layer header, IP version or IPv4 header length not as expected.
+    BadHeader,
+    /// An IPv4 option runs past the header or has an impossible length.
+    BadOptions,
+    /// The IP or UDP length disagrees with the size of the packet.
+    BadLength,
+    /// The reserved IPv4 flag bit is set, see
+    /// [`ParseOptions::reject_reserved_flag`].
+    ReservedFlagSet,
+    /// Carries another protocol than UDP.
+    NotUdp,
+    /// The IPv4 header checksum does not add up.
+    BadIpChecksum,
+    /// The UDP checksum does not add up, or is missing over IPv6.
+    BadUdpChecksum,
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            ParseError::TooShort => "packet too short",
+            ParseError::BadHeader => "malformed header",
+            ParseError::BadOptions => "malformed IP options",
+            ParseError::BadLength => "length mismatch",
+            ParseError::ReservedFlagSet => "reserved flag set",
+            ParseError::NotUdp => "not UDP",
+            ParseError::BadIpChecksum => "bad IP header checksum",
+            ParseError::BadUdpChecksum => "bad UDP checksum",
+        })
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Addresses, ports and payload of a UDP packet accepted by the parsers
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ParsedUdp<'a, A = Ipv4Addr> {
+    pub src_ip: A,
+    pub dst_ip: A,
+    pub src_port: u16,
+    pub dst_port: u16,
+    /// Type of service byte, or the IPv6 traffic class: DSCP in the upper
+    /// six bits, ECN in the lower two.
+    pub tos: u8,
+    pub payload: &'a [u8],
+}
+
+impl<'a, A: Into<IpAddr>> ParsedUdp<'a, A> {
+    /// The same with family independent addresses
+    pub fn into_ip_addr(self) -> ParsedUdp<'a, IpAddr> {
+        ParsedUdp {
+            src_ip: self.src_ip.into(),
+            dst_ip: self.dst_ip.into(),
+            src_port: self.src_port,
+            dst_port: self.dst_port,
+            tos: self.tos,
+            payload: self.payload,
+        }
+    }
 }
 
 /// Parses a raw IPv4 UDP packet and extracts relevant information
-pub fn parse_ipv4_udp_packet(packet: &[u8]) -> Option<(Ipv4Addr, Ipv4Addr, u16, u16, &[u8])> {
+pub fn parse_ipv4_udp_packet(packet: &[u8]) -> Result<ParsedUdp<'_>, ParseError> {
+    parse_ipv4_udp_packet_with(packet, &ParseOptions::default())
+}
+
+/// Parses a raw IPv4 UDP packet with the given strictness
+pub fn parse_ipv4_udp_packet_with<'a>(
+    packet: &'a [u8],
+    opts: &ParseOptions,
+) -> Result<ParsedUdp<'a>, ParseError> {
+    let packet = strip_link_layer(packet, opts.link_layer).ok_or(ParseError::BadHeader)?;
     if packet.len() < IPV4_HEADER_LEN + UDP_HEADER_LEN {
-        println!("Packet too short to be a valid IPv4 UDP packet.");
-        return None;
+        debug!("Packet too short to be a valid IPv4 UDP packet.");
+        return Err(ParseError::TooShort);
     }
 
     // Extract IPv4 Header Fields
     let ihl = (packet[0] & 0x0F) as usize * 4;
     if ihl < IPV4_HEADER_LEN {
-        println!("Invalid IPv4 header length: {ihl}");
-        return None;
+        debug!("Invalid IPv4 header length: {ihl}");
+        return Err(ParseError::BadHeader);
+    }
+    if packet.len() < ihl + UDP_HEADER_LEN {
+        debug!("IPv4 header length {ihl} leaves no room for UDP in {} bytes", packet.len());
+        return Err(ParseError::TooShort);
+    }
+    if !ipv4_options_valid(&packet[IPV4_HEADER_LEN..ihl]) {
+        debug!("Malformed IPv4 options");
+        return Err(ParseError::BadOptions);
     }
 
+    // Bytes past the total length are link layer padding, e.g. up to the
+    // Ethernet minimum frame size, and are ignored.
     let total_length = u16::from_be_bytes([packet[2], packet[3]]) as usize;
-    if total_length != packet.len() {
+    if total_length > packet.len() || total_length < ihl + UDP_HEADER_LEN {
         let pkt_len = packet.len();
-        println!("Packet length mismatch: Expected {total_length}, Found {pkt_len}");
-        return None;
+        debug!("Packet length mismatch: Expected {total_length}, Found {pkt_len}");
+        return Err(ParseError::BadLength);
+    }
+    let packet = &packet[..total_length];
+
+    if opts.reject_reserved_flag && packet[6] & 0x80 != 0 {
+        debug!("Reserved IPv4 flag bit set");
+        return Err(ParseError::ReservedFlagSet);
     }
 
     let protocol = packet[9];
     if protocol != 17 {
-        println!("Not a UDP packet (protocol = {protocol}).");
-        return None;
+        debug!("Not a UDP packet (protocol = {protocol}).");
+        return Err(ParseError::NotUdp);
     }
 
     let src_ip = Ipv4Addr::new(packet[12], packet[13], packet[14], packet[15]);
@@ -129,8 +565,8 @@ pub fn parse_ipv4_udp_packet(packet: &[u8]) -> Option<(Ipv4Addr, Ipv4Addr, u16,
     // Verify IPv4 Header Checksum
     let ip_checksum = checksum(&packet[..ihl]);
     if ip_checksum != 0 {
-        println!("Invalid IPv4 header checksum: {ip_checksum}");
-        return None;
+        debug!("Invalid IPv4 header checksum: {ip_checksum}");
+        return Err(ParseError::BadIpChecksum);
     }
 
     // Extract UDP Header Fields
@@ -140,41 +576,258 @@ pub fn parse_ipv4_udp_packet(packet: &[u8]) -> Option<(Ipv4Addr, Ipv4Addr, u16,
     let udp_length = u16::from_be_bytes([packet[udp_offset + 4], packet[udp_offset + 5]]) as usize;
 
     if udp_length < UDP_HEADER_LEN || udp_offset + udp_length > packet.len() {
-        println!(
+        debug!(
             "UDP length mismatch: Expected {}, Packet size {}",
             udp_length,
             packet.len()
         );
-        return None;
+        return Err(ParseError::BadLength);
     }
 
-    let udp_checksum = u16::from_be_bytes([packet[udp_offset + 6], packet[udp_offset + 7]]);
-    let payload = &packet[udp_offset + UDP_HEADER_LEN..udp_offset + udp_length];
-
-    // Compute UDP checksum (including pseudo-header)
-    if udp_checksum != 0 {
-        let mut pseudo_header = Vec::new();
-        pseudo_header.extend_from_slice(&src_ip.octets());
-        pseudo_header.extend_from_slice(&dst_ip.octets());
-        pseudo_header.push(0);
-        pseudo_header.push(17); // Protocol (UDP)
-        pseudo_header.extend_from_slice(
-            &u16::try_from(udp_length)
-                .expect("UDP segment too long")
-                .to_be_bytes(),
-        );
-        pseudo_header.extend_from_slice(&packet[udp_offset..udp_offset + udp_length]);
+    let segment = &packet[udp_offset..udp_offset + udp_length];
+    if opts.verify_checksums && !udp_checksum_valid(src_ip, dst_ip, segment) {
+        let udp_checksum = u16::from_be_bytes([segment[6], segment[7]]);
+        debug!("Invalid UDP checksum {udp_checksum:#06x}");
+        return Err(ParseError::BadUdpChecksum);
+    }
+    let payload = &segment[UDP_HEADER_LEN..];
 
-        let computed_udp_checksum = checksum(&pseudo_header);
-        if udp_checksum != 0 && computed_udp_checksum != 0 {
-            println!(
-                "Invalid UDP checksum: Expected {udp_checksum}, Computed {computed_udp_checksum}"
-            );
-            return None;
-        }
+    Ok(ParsedUdp {
+        src_ip,
+        dst_ip,
+        src_port,
+        dst_port,
+        tos: packet[1],
+        payload,
+    })
+}
+
+/// One's complement checksum of a UDP segment over the IPv6 pseudo-header
+fn udp6_pseudo_checksum(src_ip: Ipv6Addr, dst_ip: Ipv6Addr, segment: &[u8]) -> u16 {
+    udp6_pseudo_checksum_split(src_ip, dst_ip, segment, &[])
+}
+
+/// [`udp6_pseudo_checksum`] of the segment `head` followed by `rest`, with
+/// `head` of even length.
+fn udp6_pseudo_checksum_split(src_ip: Ipv6Addr, dst_ip: Ipv6Addr, head: &[u8], rest: &[u8]) -> u16 {
+    let mut sum = sum_words(0, &src_ip.octets());
+    sum = sum_words(sum, &dst_ip.octets());
+    sum = sum_words(
+        sum,
+        &u32::try_from(head.len() + rest.len())
+            .expect("UDP segment too long")
+            .to_be_bytes(),
+    );
+    sum = sum_words(sum, &[0, 0, 0, 17]); // Zero bytes + next header (UDP)
+    fold_checksum(sum_words(sum_words(sum, head), rest))
+}
+
+/// Creates a valid IPv6 UDP packet.  Unlike over IPv4, the UDP checksum is
+/// mandatory and always computed.
+pub fn create_ipv6_udp_packet(
+    payload: &[u8],
+    src_ip: Ipv6Addr,
+    dst_ip: Ipv6Addr,
+    src_port: u16,
+    dst_port: u16,
+) -> Vec<u8> {
+    let mut packet = vec![0u8; IPV6_HEADER_LEN + UDP_HEADER_LEN + payload.len()];
+    encode_ipv6_udp_into(&mut packet, payload, src_ip, dst_ip, src_port, dst_port)
+        .expect("UDP segment too long");
+    packet
+}
+
+/// Like [`create_ipv6_udp_packet`], but writes the packet to the start of
+/// `dst` instead of allocating it.  Returns the length of the packet.
+pub fn encode_ipv6_udp_into(
+    dst: &mut [u8],
+    payload: &[u8],
+    src_ip: Ipv6Addr,
+    dst_ip: Ipv6Addr,
+    src_port: u16,
+    dst_port: u16,
+) -> Result<usize, EncodeError> {
+    let needed = IPV6_HEADER_LEN + UDP_HEADER_LEN + payload.len();
+    let header_len = encode_ipv6_udp_header(dst, payload, src_ip, dst_ip, src_port, dst_port).map_err(|e| match e {
+        EncodeError::BufferTooSmall { .. } => EncodeError::BufferTooSmall { needed },
+        e => e,
+    })?;
+    dst.get_mut(header_len..needed)
+        .ok_or(EncodeError::BufferTooSmall { needed })?
+        .copy_from_slice(payload);
+    Ok(needed)
+}
+
+/// IPv6 counterpart of [`encode_ipv4_udp_header_with`].
+pub fn encode_ipv6_udp_header(
+    dst: &mut [u8],
+    payload: &[u8],
+    src_ip: Ipv6Addr,
+    dst_ip: Ipv6Addr,
+    src_port: u16,
+    dst_port: u16,
+) -> Result<usize, EncodeError> {
+    let udp_length = u16::try_from(UDP_HEADER_LEN + payload.len()).map_err(|_| EncodeError::TooLong)?;
+    let header_len = IPV6_HEADER_LEN + UDP_HEADER_LEN;
+    let packet = dst
+        .get_mut(..header_len)
+        .ok_or(EncodeError::BufferTooSmall { needed: header_len })?;
+
+    // IPv6 Header
+    packet[0..4].copy_from_slice(&[0x60, 0, 0, 0]); // Version (6), traffic class and flow label zero
+    packet[4..6].copy_from_slice(&udp_length.to_be_bytes()); // Payload length
+    packet[6] = 17; // Next header (UDP)
+    packet[7] = 64; // Hop limit
+    packet[8..24].copy_from_slice(&src_ip.octets()); // Source IP
+    packet[24..40].copy_from_slice(&dst_ip.octets()); // Destination IP
+
+    // UDP Header
+    let udp_offset = IPV6_HEADER_LEN;
+    packet[udp_offset..udp_offset + 2].copy_from_slice(&src_port.to_be_bytes());
+    packet[udp_offset + 2..udp_offset + 4].copy_from_slice(&dst_port.to_be_bytes());
+    packet[udp_offset + 4..udp_offset + 6].copy_from_slice(&udp_length.to_be_bytes());
+    packet[udp_offset + 6..udp_offset + 8].copy_from_slice(&[0, 0]);
+
+    let udp_checksum = match udp6_pseudo_checksum_split(src_ip, dst_ip, &packet[udp_offset..], payload) {
+        0 => 0xFFFF,
+        c => c,
+    };
+    packet[udp_offset + 6..udp_offset + 8].copy_from_slice(&udp_checksum.to_be_bytes());
+    Ok(header_len)
+}
+
+/// Parses a raw IPv6 UDP packet without extension headers and extracts
+/// relevant information
+pub fn parse_ipv6_udp_packet(packet: &[u8]) -> Result<ParsedUdp<'_, Ipv6Addr>, ParseError> {
+    if packet.len() < IPV6_HEADER_LEN + UDP_HEADER_LEN {
+        debug!("Packet too short to be a valid IPv6 UDP packet.");
+        return Err(ParseError::TooShort);
+    }
+    if packet[0] >> 4 != 6 {
+        debug!("Not an IPv6 packet (version = {}).", packet[0] >> 4);
+        return Err(ParseError::BadHeader);
+    }
+    let payload_length = usize::from(u16::from_be_bytes([packet[4], packet[5]]));
+    if IPV6_HEADER_LEN + payload_length != packet.len() {
+        let pkt_len = packet.len();
+        debug!("Packet length mismatch: Expected {}, Found {pkt_len}", IPV6_HEADER_LEN + payload_length);
+        return Err(ParseError::BadLength);
+    }
+    if packet[6] != 17 {
+        debug!("Not a UDP packet (next header = {}).", packet[6]);
+        return Err(ParseError::NotUdp);
+    }
+    let src_ip = Ipv6Addr::from(<[u8; 16]>::try_from(&packet[8..24]).unwrap());
+    let dst_ip = Ipv6Addr::from(<[u8; 16]>::try_from(&packet[24..40]).unwrap());
+
+    let udp = &packet[IPV6_HEADER_LEN..];
+    let src_port = u16::from_be_bytes([udp[0], udp[1]]);
+    let dst_port = u16::from_be_bytes([udp[2], udp[3]]);
+    let udp_length = usize::from(u16::from_be_bytes([udp[4], udp[5]]));
+    if udp_length != udp.len() {
+        debug!("UDP length mismatch: Expected {udp_length}, Packet size {}", udp.len());
+        return Err(ParseError::BadLength);
+    }
+    // Zero is not allowed over IPv6 (RFC 8200).
+    let udp_checksum = u16::from_be_bytes([udp[6], udp[7]]);
+    let computed_udp_checksum = udp6_pseudo_checksum(src_ip, dst_ip, udp);
+    if udp_checksum == 0 || computed_udp_checksum != 0 {
+        debug!("Invalid UDP checksum: Expected {udp_checksum}, Computed {computed_udp_checksum}");
+        return Err(ParseError::BadUdpChecksum);
+    }
+
+    Ok(ParsedUdp {
+        src_ip,
+        dst_ip,
+        src_port,
+        dst_port,
+        tos: packet[0] << 4 | packet[1] >> 4,
+        payload: &udp[UDP_HEADER_LEN..],
+    })
+}
+
+/// Hop limit of an IPv6 packet accepted by [`parse_ipv6_udp_packet`].
+pub fn ipv6_hop_limit(packet: &[u8]) -> u8 {
+    packet[7]
+}
+
+/// Time to live of an IPv4 packet accepted by [`parse_ipv4_udp_packet`].
+pub fn ipv4_ttl(packet: &[u8]) -> u8 {
+    packet[8]
+}
+
+/// DSCP codepoint of a type of service byte.
+pub fn dscp(tos: u8) -> u8 {
+    tos >> 2
+}
+
+/// ECN codepoint of a type of service byte.
+pub fn ecn(tos: u8) -> u8 {
+    tos & 0x03
+}
+
+/// Compare a decoded packet against what went into it.
+fn check_round_trip<A: PartialEq + std::fmt::Display>(
+    what: &str,
+    parsed: Result<ParsedUdp<'_, A>, ParseError>,
+    sent: (A, A, u16, u16, &[u8]),
+) -> Result<(), String> {
+    let p = parsed.map_err(|e| format!("{what}: packet rejected by the parser, {e}"))?;
+    let (src_ip, dst_ip, src_port, dst_port, payload) = sent;
+    if p.src_ip != src_ip || p.dst_ip != dst_ip {
+        return Err(format!("{what}: addresses {} -> {} came back as {} -> {}", src_ip, dst_ip, p.src_ip, p.dst_ip));
+    }
+    if (p.src_port, p.dst_port) != (src_port, dst_port) {
+        return Err(format!("{what}: ports {src_port} -> {dst_port} came back as {} -> {}", p.src_port, p.dst_port));
+    }
+    if p.payload != payload {
+        return Err(format!("{what}: payload of {} bytes came back as {} bytes differing", payload.len(), p.payload.len()));
+    }
+    Ok(())
+}
+
+/// Loop a known payload through the encoders and parsers, without any
+/// socket, and check that everything comes back unchanged.  Meant for
+/// commissioning on the target hardware, where it catches endianness and
+/// header layout mistakes.  Returns the first failure.
+pub fn self_test() -> Result<(), String> {
+    // Odd length for the checksum's trailing byte, ports and addresses which
+    // read differently when byte swapped.
+    let payload: Vec<u8> = (0..=255u8).chain([0xa5]).collect();
+    let (src, dst) = (Ipv4Addr::new(192, 0, 2, 1), Ipv4Addr::new(198, 51, 100, 7));
+    let (sport, dport) = (0x1234, 0xabcd);
+
+    let packet = create_ipv4_udp_packet(&payload, src, dst, sport, dport);
+    if packet[0] != 0x45 || packet[9] != 17 {
+        return Err(format!("IPv4: header starts {:02x?}, expected version 4, IHL 5 and protocol UDP", &packet[..10]));
+    }
+    if u16::from_be_bytes([packet[2], packet[3]]) as usize != packet.len() {
+        return Err("IPv4: total length is not the packet length in network byte order".to_string());
+    }
+    if checksum(&packet[..IPV4_HEADER_LEN]) != 0 {
+        return Err("IPv4: header checksum does not verify".to_string());
+    }
+    check_round_trip("IPv4", parse_ipv4_udp_packet(&packet), (src, dst, sport, dport, &payload))?;
+
+    let opts = Ipv4Options {
+        udp_checksum: true,
+        ..Ipv4Options::default()
+    };
+    let mut packet = create_ipv4_udp_packet_with(&payload, src, dst, sport, dport, &opts);
+    if packet[IPV4_HEADER_LEN + 6..IPV4_HEADER_LEN + 8] == [0, 0] {
+        return Err("IPv4: UDP checksum requested but left zero".to_string());
+    }
+    check_round_trip("IPv4 with UDP checksum", parse_ipv4_udp_packet(&packet), (src, dst, sport, dport, &payload))?;
+    *packet.last_mut().unwrap() ^= 0xff;
+    if parse_ipv4_udp_packet(&packet) != Err(ParseError::BadUdpChecksum) {
+        return Err("IPv4: corrupted payload passed the UDP checksum".to_string());
     }
 
-    Some((src_ip, dst_ip, src_port, dst_port, payload))
+    let src6 = Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1);
+    let dst6 = Ipv6Addr::new(0x2001, 0xdb8, 0x1234, 0, 0, 0, 0xabcd, 2);
+    let packet = create_ipv6_udp_packet(&payload, src6, dst6, sport, dport);
+    check_round_trip("IPv6", parse_ipv6_udp_packet(&packet), (src6, dst6, sport, dport, &payload))?;
+    Ok(())
 }
 
 // Run a couple of test cases.
@@ -183,11 +836,11 @@ pub fn parse_ipv4_udp_packet(packet: &[u8]) -> Option<(Ipv4Addr, Ipv4Addr, u16,
 mod tests {
 
     use crate::udp;
-    use crate::Ipv4Addr;
+    use std::net::{Ipv4Addr, Ipv6Addr};
 
     fn analyze_pkt(pkt: &[u8]) {
         match udp::parse_ipv4_udp_packet(pkt) {
-            Some((src_ip, dst_ip, src_port, dst_port, payload)) => {
+            Ok(udp::ParsedUdp { src_ip, dst_ip, src_port, dst_port, payload, .. }) => {
                 println!("Valid IPv4 UDP Packet:");
                 println!("  Source IP: {}", src_ip);
                 println!("  Destination IP: {}", dst_ip);
@@ -195,13 +848,18 @@ mod tests {
                 println!("  Destination Port: {}", dst_port);
                 println!("  Payload: {:?}", String::from_utf8_lossy(payload));
             }
-            None => {
-                println!("Invalid packet.");
+            Err(e) => {
+                println!("Invalid packet: {e}");
                 panic!();
             }
         }
     }
 
+    #[test]
+    fn self_test_passes() {
+        assert_eq!(udp::self_test(), Ok(()));
+    }
+
     #[test]
     fn example_raw_decode() {
         // Example raw UDP packet (in hex)
@@ -217,6 +875,592 @@ mod tests {
         analyze_pkt(&raw_packet);
     }
 
+    /// Insert `opts` (a multiple of 4 bytes) as IPv4 options into `packet`.
+    fn with_ip_options(packet: &[u8], opts: &[u8]) -> Vec<u8> {
+        let mut out = packet[..20].to_vec();
+        out.extend_from_slice(opts);
+        out.extend_from_slice(&packet[20..]);
+        out[0] = 0x40 | (u8::try_from(20 + opts.len()).unwrap() / 4);
+        let total = u16::try_from(out.len()).unwrap();
+        out[2..4].copy_from_slice(&total.to_be_bytes());
+        out[10..12].copy_from_slice(&[0, 0]);
+        let ihl = usize::from(out[0] & 0x0F) * 4;
+        let csum = udp::checksum(&out[..ihl]);
+        out[10..12].copy_from_slice(&csum.to_be_bytes());
+        out
+    }
+
+    #[test]
+    fn ip_options_are_walked() {
+        let src_ip = Ipv4Addr::new(192, 168, 1, 100);
+        let dst_ip = Ipv4Addr::new(192, 168, 1, 1);
+        let packet = udp::create_ipv4_udp_packet(b"opts", src_ip, dst_ip, 1000, 2000);
+
+        // NOP, NOP, then a 2-byte option: well formed.
+        let good = with_ip_options(&packet, &[1, 1, 0x94, 2]);
+        let udp::ParsedUdp { payload, .. } = udp::parse_ipv4_udp_packet(&good).unwrap();
+        assert_eq!(payload, b"opts");
+
+        // Timestamp option claiming 8 bytes in a 4-byte options area.
+        let bad = with_ip_options(&packet, &[0x44, 8, 5, 0]);
+        assert_eq!(udp::parse_ipv4_udp_packet(&bad), Err(udp::ParseError::BadOptions));
+
+        // Option length below the minimum of 2.
+        let bad = with_ip_options(&packet, &[0x94, 1, 0, 0]);
+        let err = udp::parse_ipv4_udp_packet(&bad).unwrap_err();
+        assert_eq!((err, err.to_string()), (udp::ParseError::BadOptions, "malformed IP options".to_string()));
+    }
+
+    #[test]
+    fn trailing_padding_is_ignored() {
+        let src_ip = Ipv4Addr::new(192, 168, 1, 100);
+        let dst_ip = Ipv4Addr::new(192, 168, 1, 1);
+        let opts = udp::Ipv4Options {
+            udp_checksum: true,
+            ..Default::default()
+        };
+        let packet = udp::create_ipv4_udp_packet_with(b"pad", src_ip, dst_ip, 1000, 2000, &opts);
+        // A 4-byte option, then padding as a NIC adds it to short frames.
+        let mut padded = with_ip_options(&packet, &[0x94, 4, 0, 0]);
+        padded.resize(60, 0xAA);
+        let expected = udp::ParsedUdp {
+            src_ip,
+            dst_ip,
+            src_port: 1000,
+            dst_port: 2000,
+            tos: 0,
+            payload: b"pad",
+        };
+        assert_eq!(udp::parse_ipv4_udp_packet(&padded), Ok(expected));
+
+        // A total length beyond the buffer is still truncation.
+        assert_eq!(udp::parse_ipv4_udp_packet(&padded[..packet.len() + 3]), Err(udp::ParseError::BadLength));
+        // Options leaving no room for the UDP header within the total length.
+        let mut short = with_ip_options(&packet[..20], &[1; 8]);
+        short.resize(40, 0);
+        assert_eq!(udp::parse_ipv4_udp_packet(&short), Err(udp::ParseError::BadLength));
+    }
+
+    #[test]
+    fn builder_checksums_options() {
+        let src_ip = Ipv4Addr::new(192, 168, 1, 100);
+        let dst_ip = Ipv4Addr::new(192, 168, 1, 1);
+        let opts = udp::Ipv4Options {
+            // Record route with room for one address, then end of list.
+            options: vec![7, 7, 4, 0, 0, 0, 0, 0],
+            ..Default::default()
+        };
+        let mut packet = udp::create_ipv4_udp_packet_with(b"opts", src_ip, dst_ip, 1000, 2000, &opts);
+        assert_eq!(packet[0], 0x47);
+        let parsed = udp::parse_ipv4_udp_packet(&packet).unwrap();
+        assert_eq!((parsed.src_port, parsed.dst_port, parsed.payload), (1000, 2000, &b"opts"[..]));
+
+        // A checksum over the fixed 20 bytes only does not verify.
+        packet[10..12].copy_from_slice(&[0, 0]);
+        let short = udp::checksum(&packet[..20]);
+        packet[10..12].copy_from_slice(&short.to_be_bytes());
+        assert_eq!(udp::parse_ipv4_udp_packet(&packet), Err(udp::ParseError::BadIpChecksum));
+    }
+
+    #[test]
+    fn udp_checksum_round_trip() {
+        let src_ip = Ipv4Addr::new(192, 168, 1, 100);
+        let dst_ip = Ipv4Addr::new(192, 168, 1, 1);
+        let opts = udp::Ipv4Options {
+            udp_checksum: true,
+            ..Default::default()
+        };
+        let mut packet = udp::create_ipv4_udp_packet_with(b"summed", src_ip, dst_ip, 1000, 2000, &opts);
+        assert_ne!(&packet[26..28], &[0, 0]);
+        let udp::ParsedUdp { payload: data, .. } = udp::parse_ipv4_udp_packet(&packet).unwrap();
+        assert_eq!(data, b"summed");
+        // Corrupting the payload is now detected.
+        packet[28] ^= 1;
+        assert_eq!(udp::parse_ipv4_udp_packet(&packet), Err(udp::ParseError::BadUdpChecksum));
+
+        // Choose a payload word that makes the checksum come out as zero, which
+        // has to be sent as 0xFFFF.
+        let plain = udp::create_ipv4_udp_packet(&[0, 0], src_ip, dst_ip, 1000, 2000);
+        let zeroing = udp::udp_pseudo_checksum(src_ip, dst_ip, &plain[20..]);
+        let packet = udp::create_ipv4_udp_packet_with(&zeroing.to_be_bytes(), src_ip, dst_ip, 1000, 2000, &opts);
+        assert_eq!(&packet[26..28], &[0xFF, 0xFF]);
+        assert!(udp::parse_ipv4_udp_packet(&packet).is_ok());
+    }
+
+    #[test]
+    fn lax_parsing_skips_udp_checksum() {
+        let src_ip = Ipv4Addr::new(192, 168, 1, 100);
+        let dst_ip = Ipv4Addr::new(192, 168, 1, 1);
+        let opts = udp::Ipv4Options {
+            udp_checksum: true,
+            ..Default::default()
+        };
+        let mut packet = udp::create_ipv4_udp_packet_with(b"offload", src_ip, dst_ip, 1000, 2000, &opts);
+        packet[26] ^= 0x5a;
+        let strict = udp::ParseOptions::default();
+        let lax = udp::ParseOptions {
+            verify_checksums: false,
+            ..Default::default()
+        };
+        assert!(strict.verify_checksums);
+        assert_eq!(udp::parse_ipv4_udp_packet_with(&packet, &strict), Err(udp::ParseError::BadUdpChecksum));
+        let parsed = udp::parse_ipv4_udp_packet_with(&packet, &lax).unwrap();
+        assert_eq!(parsed.payload, b"offload");
+
+        // A zero checksum stands for none and passes either way.
+        packet[26..28].copy_from_slice(&[0, 0]);
+        assert!(udp::parse_ipv4_udp_packet_with(&packet, &strict).is_ok());
+
+        // The IP header checksum still counts.
+        packet[10] ^= 1;
+        assert_eq!(udp::parse_ipv4_udp_packet_with(&packet, &lax), Err(udp::ParseError::BadIpChecksum));
+    }
+
+    #[test]
+    fn parse_errors_name_the_reason() {
+        let src_ip = Ipv4Addr::new(192, 168, 1, 100);
+        let dst_ip = Ipv4Addr::new(192, 168, 1, 1);
+        let packet = udp::create_ipv4_udp_packet(b"why", src_ip, dst_ip, 1000, 2000);
+        assert_eq!(udp::parse_ipv4_udp_packet(&packet[..27]), Err(udp::ParseError::TooShort));
+
+        let mut tcp = packet.clone();
+        tcp[9] = 6;
+        tcp[10..12].copy_from_slice(&[0, 0]);
+        let sum = udp::checksum(&tcp[..20]);
+        tcp[10..12].copy_from_slice(&sum.to_be_bytes());
+        assert_eq!(udp::parse_ipv4_udp_packet(&tcp), Err(udp::ParseError::NotUdp));
+
+        let mut evil = packet.clone();
+        evil[6] |= 0x80;
+        evil[10..12].copy_from_slice(&[0, 0]);
+        let sum = udp::checksum(&evil[..20]);
+        evil[10..12].copy_from_slice(&sum.to_be_bytes());
+        let strict = udp::ParseOptions {
+            reject_reserved_flag: true,
+            ..Default::default()
+        };
+        assert_eq!(udp::parse_ipv4_udp_packet_with(&evil, &strict), Err(udp::ParseError::ReservedFlagSet));
+        assert_eq!(udp::ParseError::BadIpChecksum.to_string(), "bad IP header checksum");
+    }
+
+    #[test]
+    fn ipv6_round_trip() {
+        let src_ip: Ipv6Addr = "2001:db8::1".parse().unwrap();
+        let dst_ip: Ipv6Addr = "2001:db8::2".parse().unwrap();
+        let mut packet = udp::create_ipv6_udp_packet(b"six", src_ip, dst_ip, 1000, 2000);
+        assert_eq!(packet.len(), 40 + 8 + 3);
+        assert_eq!(udp::ipv6_hop_limit(&packet), 64);
+        let expected = udp::ParsedUdp {
+            src_ip,
+            dst_ip,
+            src_port: 1000,
+            dst_port: 2000,
+            tos: 0,
+            payload: &b"six"[..],
+        };
+        assert_eq!(udp::parse_ipv6_udp_packet(&packet), Ok(expected));
+        assert_eq!(udp::parse_ipv4_udp_packet(&packet), Err(udp::ParseError::BadHeader));
+
+        // Traffic class 0xB9 straddles the first two bytes.
+        let mut marked = packet.clone();
+        marked[0..2].copy_from_slice(&[0x6B, 0x90]);
+        assert_eq!(udp::parse_ipv6_udp_packet(&marked).unwrap().tos, 0xB9);
+
+        packet[50] ^= 1;
+        assert_eq!(udp::parse_ipv6_udp_packet(&packet), Err(udp::ParseError::BadUdpChecksum));
+        // A zero checksum is not allowed over IPv6.
+        packet[46..48].copy_from_slice(&[0, 0]);
+        assert_eq!(udp::parse_ipv6_udp_packet(&packet), Err(udp::ParseError::BadUdpChecksum));
+    }
+
+    #[test]
+    fn tos_survives_parse() {
+        let src_ip = Ipv4Addr::new(192, 168, 1, 100);
+        let dst_ip = Ipv4Addr::new(192, 168, 1, 1);
+        for (dscp, ecn) in [(0, 0), (46, 1), (10, 2), (46, 3)] {
+            let mut packet = udp::create_ipv4_udp_packet(b"tos", src_ip, dst_ip, 1000, 2000);
+            packet[1] = dscp << 2 | ecn;
+            packet[10..12].copy_from_slice(&[0, 0]);
+            let csum = udp::checksum(&packet[..20]);
+            packet[10..12].copy_from_slice(&csum.to_be_bytes());
+
+            let tos = udp::parse_ipv4_udp_packet(&packet).unwrap().tos;
+            assert_eq!((udp::dscp(tos), udp::ecn(tos)), (dscp, ecn));
+        }
+    }
+
+    #[test]
+    fn builder_sets_ttl_tos_and_df() {
+        let src_ip = Ipv4Addr::new(192, 168, 1, 100);
+        let dst_ip = Ipv4Addr::new(192, 168, 1, 1);
+        let packet = udp::create_ipv4_udp_packet(b"hdr", src_ip, dst_ip, 1000, 2000);
+        assert_eq!((packet[1], &packet[6..8], packet[8]), (0, &[0x40, 0][..], 64));
+
+        let opts = udp::Ipv4Options {
+            ttl: 255,
+            dscp: 46,
+            ecn: 1,
+            dont_fragment: false,
+            ..Default::default()
+        };
+        let packet = udp::create_ipv4_udp_packet_with(b"hdr", src_ip, dst_ip, 1000, 2000, &opts);
+        assert_eq!((packet[1], &packet[6..8], packet[8]), (0xB9, &[0, 0][..], 255));
+        assert_eq!(udp::checksum(&packet[..20]), 0);
+        assert!(udp::parse_ipv4_udp_packet(&packet).is_ok());
+    }
+
+    /// RFC 1071 checksum the slow way, folding the carry after every add.
+    fn reference_checksum(data: &[u8]) -> u16 {
+        let mut sum: u16 = 0;
+        for chunk in data.chunks(2) {
+            let word = u16::from_be_bytes([chunk[0], *chunk.get(1).unwrap_or(&0)]);
+            let (s, carry) = sum.overflowing_add(word);
+            sum = s + u16::from(carry);
+        }
+        !sum
+    }
+
+    #[test]
+    fn checksum_pads_odd_length() {
+        // 0x0102 + 0x0300
+        assert_eq!(udp::checksum(&[0x01, 0x02, 0x03]), !0x0402);
+        assert_eq!(udp::checksum(&[0xab]), !0xab00);
+        let data: Vec<u8> = (0..101u32).map(|i| (i * 37 + 11) as u8).collect();
+        let mut padded = data.clone();
+        padded.push(0);
+        assert_eq!(udp::checksum(&data), udp::checksum(&padded));
+    }
+
+    #[test]
+    fn checksum_folds_carries_repeatedly() {
+        // The words sum to 0x1ffff, whose first fold gives 0x10000 and
+        // carries once more.
+        assert_eq!(udp::checksum(&[0xff, 0xff, 0xff, 0xff, 0x00, 0x01]), !0x0001);
+        // All ones is negative zero, whose complement is 0.
+        assert_eq!(udp::checksum(&[0xff; 2]), 0);
+        assert_eq!(udp::checksum(&[0xff; 65534]), 0);
+        assert_eq!(udp::checksum(&[]), 0xffff);
+    }
+
+    #[test]
+    fn checksum_matches_reference() {
+        for len in 0..300u32 {
+            let data: Vec<u8> = (0..len).map(|i| (i * 151 + len) as u8 | 0x80).collect();
+            assert_eq!(udp::checksum(&data), reference_checksum(&data), "length {len}");
+        }
+        let big: Vec<u8> = (0..65535u32).map(|i| (i ^ (i >> 8)) as u8).collect();
+        assert_eq!(udp::checksum(&big), reference_checksum(&big));
+    }
+
+    #[test]
+    fn incremental_checksum_update() {
+        let src_ip = Ipv4Addr::new(192, 168, 1, 100);
+        let dst_ip = Ipv4Addr::new(192, 168, 1, 1);
+        let mut packet = udp::create_ipv4_udp_packet(b"hdr", src_ip, dst_ip, 1000, 2000);
+        // Decrement the TTL, which shares a word with the protocol.
+        let old_word = u16::from_be_bytes([packet[8], packet[9]]);
+        let old_sum = u16::from_be_bytes([packet[10], packet[11]]);
+        packet[8] -= 1;
+        let new_word = u16::from_be_bytes([packet[8], packet[9]]);
+        let sum = udp::checksum_update(old_sum, old_word, new_word);
+        packet[10..12].copy_from_slice(&[0, 0]);
+        assert_eq!(sum, udp::checksum(&packet[..20]));
+    }
+
+    #[test]
+    fn patched_payload_matches_full_checksum() {
+        let src_ip = Ipv4Addr::new(192, 168, 1, 100);
+        let dst_ip = Ipv4Addr::new(192, 168, 1, 1);
+        let opts = udp::Ipv4Options {
+            udp_checksum: true,
+            ..Default::default()
+        };
+        let payload = b"the quick brown fox";
+        // (offset, new bytes): aligned, odd offset, odd length up to the odd
+        // final byte, and the whole payload.
+        let edits: [(usize, &[u8]); 4] = [(0, b"TH"), (5, b"Q"), (14, b"F0X!!"), (0, b"jumps over the dog!")];
+        for (offset, new_bytes) in edits {
+            let mut expected = payload.to_vec();
+            expected[offset..offset + new_bytes.len()].copy_from_slice(new_bytes);
+
+            let mut packet = udp::create_ipv4_udp_packet_with(payload, src_ip, dst_ip, 1000, 2000, &opts);
+            udp::patch_udp_payload(&mut packet, offset, new_bytes);
+            let rebuilt = udp::create_ipv4_udp_packet_with(&expected, src_ip, dst_ip, 1000, 2000, &opts);
+            assert_eq!(packet, rebuilt);
+
+            let src6: Ipv6Addr = "2001:db8::1".parse().unwrap();
+            let dst6: Ipv6Addr = "2001:db8::2".parse().unwrap();
+            let mut packet = udp::create_ipv6_udp_packet(payload, src6, dst6, 1000, 2000);
+            udp::patch_udp_payload(&mut packet, offset, new_bytes);
+            assert_eq!(packet, udp::create_ipv6_udp_packet(&expected, src6, dst6, 1000, 2000));
+        }
+
+        // Without a UDP checksum there is nothing to update.
+        let mut packet = udp::create_ipv4_udp_packet(payload, src_ip, dst_ip, 1000, 2000);
+        udp::patch_udp_payload(&mut packet, 4, b"slow!");
+        assert_eq!(&packet[26..28], &[0, 0]);
+        assert_eq!(udp::parse_ipv4_udp_packet(&packet).unwrap().payload, b"the slow! brown fox");
+    }
+
+    #[test]
+    fn short_and_random_buffers_do_not_panic() {
+        use rand::Rng;
+
+        let parse_all = |buf: &[u8]| {
+            use udp::LinkLayer::{Ethernet, EthernetVlan, RawIp};
+            for link_layer in [RawIp, Ethernet, EthernetVlan] {
+                let opts = udp::ParseOptions {
+                    reject_reserved_flag: true,
+                    link_layer,
+                    verify_checksums: true,
+                };
+                let _ = udp::parse_ipv4_udp_packet_with(buf, &opts);
+            }
+            let _ = udp::parse_ipv6_udp_packet(buf);
+        };
+
+        // IHL 15 (60 bytes) in a packet of 24.
+        let lo = Ipv4Addr::LOCALHOST;
+        let mut crafted = udp::create_ipv4_udp_packet(&[0; 4], lo, lo, 1, 2);
+        crafted[0] = 0x4F;
+        assert!(udp::parse_ipv4_udp_packet(&crafted).is_err());
+
+        // Every prefix of a valid packet with options.
+        let opts = udp::Ipv4Options {
+            options: vec![7, 7, 4, 0, 0, 0, 0, 0],
+            ..Default::default()
+        };
+        let valid = udp::create_ipv4_udp_packet_with(b"fuzz", lo, lo, 1, 2, &opts);
+        for len in 0..valid.len() {
+            parse_all(&valid[..len]);
+        }
+
+        // Random bytes behind a plausible version and header length.
+        let mut rng = rand::rng();
+        let mut buf = [0u8; 96];
+        for _ in 0..100_000 {
+            let len = rng.random_range(0..buf.len());
+            rng.fill(&mut buf[..len]);
+            if len > 0 && rng.random_bool(0.5) {
+                buf[0] = 0x40 | (buf[0] & 0x0F);
+            }
+            if len > 3 && rng.random_bool(0.5) {
+                buf[2..4].copy_from_slice(&(len as u16).to_be_bytes());
+            }
+            parse_all(&buf[..len]);
+        }
+    }
+
+    #[test]
+    fn encode_into_matches_allocating_builders() {
+        let src_ip = Ipv4Addr::new(192, 168, 1, 100);
+        let dst_ip = Ipv4Addr::new(192, 168, 1, 1);
+        let opts = udp::Ipv4Options {
+            udp_checksum: true,
+            options: vec![1, 1, 1, 0],
+            ..Default::default()
+        };
+        // Leftovers of a previous packet must not leak into the next one.
+        let mut buf = [0xAAu8; 128];
+        let len = udp::encode_ipv4_udp_into_with(&mut buf, b"reuse", src_ip, dst_ip, 1000, 2000, &opts).unwrap();
+        let packet = udp::create_ipv4_udp_packet_with(b"reuse", src_ip, dst_ip, 1000, 2000, &opts);
+        assert_eq!(&buf[..len], &packet[..]);
+        let len = udp::encode_ipv4_udp_into(&mut buf, b"plain", src_ip, dst_ip, 1000, 2000).unwrap();
+        assert_eq!(&buf[..len], &udp::create_ipv4_udp_packet(b"plain", src_ip, dst_ip, 1000, 2000)[..]);
+
+        let src6: Ipv6Addr = "2001:db8::1".parse().unwrap();
+        let dst6: Ipv6Addr = "2001:db8::2".parse().unwrap();
+        let len = udp::encode_ipv6_udp_into(&mut buf, b"six", src6, dst6, 1000, 2000).unwrap();
+        assert_eq!(&buf[..len], &udp::create_ipv6_udp_packet(b"six", src6, dst6, 1000, 2000)[..]);
+
+        assert_eq!(
+            udp::encode_ipv4_udp_into(&mut buf[..30], b"too long", src_ip, dst_ip, 1000, 2000),
+            Err(udp::EncodeError::BufferTooSmall { needed: 36 })
+        );
+        let mut big = vec![0u8; 70_000];
+        let payload = vec![0u8; 65_530];
+        assert_eq!(
+            udp::encode_ipv4_udp_into(&mut big, &payload, src_ip, dst_ip, 1000, 2000),
+            Err(udp::EncodeError::TooLong)
+        );
+    }
+
+    #[test]
+    fn headers_match_whole_packet() {
+        let src_ip = Ipv4Addr::new(192, 168, 1, 100);
+        let dst_ip = Ipv4Addr::new(192, 168, 1, 1);
+        let opts = udp::Ipv4Options {
+            udp_checksum: true,
+            options: vec![1, 1, 1, 0],
+            ..Default::default()
+        };
+        // Odd length, so the checksum pads the payload rather than the header.
+        let payload = b"gathered";
+        let mut hdr = [0u8; udp::MAX_HEADERS_LEN];
+        let len = udp::encode_ipv4_udp_header_with(&mut hdr, &payload[1..], src_ip, dst_ip, 1000, 2000, &opts).unwrap();
+        let packet = udp::create_ipv4_udp_packet_with(&payload[1..], src_ip, dst_ip, 1000, 2000, &opts);
+        assert_eq!([&hdr[..len], &payload[1..]].concat(), packet);
+
+        let src6: Ipv6Addr = "2001:db8::1".parse().unwrap();
+        let dst6: Ipv6Addr = "2001:db8::2".parse().unwrap();
+        let len = udp::encode_ipv6_udp_header(&mut hdr, &payload[1..], src6, dst6, 1000, 2000).unwrap();
+        let packet = udp::create_ipv6_udp_packet(&payload[1..], src6, dst6, 1000, 2000);
+        assert_eq!([&hdr[..len], &payload[1..]].concat(), packet);
+
+        assert_eq!(
+            udp::encode_ipv6_udp_header(&mut hdr[..40], payload, src6, dst6, 1000, 2000),
+            Err(udp::EncodeError::BufferTooSmall { needed: 48 })
+        );
+    }
+
+    #[test]
+    fn checksum_mode_leaves_checksums_to_offload() {
+        let src_ip = Ipv4Addr::new(192, 168, 1, 100);
+        let dst_ip = Ipv4Addr::new(192, 168, 1, 1);
+        let build = |checksum| {
+            let opts = udp::Ipv4Options { udp_checksum: true, checksum, ..Default::default() };
+            udp::create_ipv4_udp_packet_with(b"offload", src_ip, dst_ip, 1000, 2000, &opts)
+        };
+
+        // Full: both are filled in and check out.
+        let full = build(udp::ChecksumMode::Full);
+        assert_ne!(full[10..12], [0, 0]);
+        assert_eq!(udp::checksum(&full[..20]), 0);
+        assert_ne!(full[26..28], [0, 0]);
+        assert!(udp::parse_ipv4_udp_packet(&full).is_ok());
+
+        // IpOnly: only the IP header checksum is left to the NIC.
+        let ip_only = build(udp::ChecksumMode::IpOnly);
+        assert_eq!(ip_only[10..12], [0, 0]);
+        assert_eq!(ip_only[26..28], full[26..28]);
+
+        let none = build(udp::ChecksumMode::None);
+        assert_eq!(none[10..12], [0, 0]);
+        assert_eq!(none[26..28], [0, 0]);
+        // Apart from the checksums, the packets are the same.
+        let mut patched = none.clone();
+        patched[10..12].copy_from_slice(&full[10..12]);
+        patched[26..28].copy_from_slice(&full[26..28]);
+        assert_eq!(patched, full);
+    }
+
+    #[test]
+    fn copies_tos_of_inner_ipv4_packet() {
+        let src_ip = Ipv4Addr::new(192, 168, 1, 100);
+        let dst_ip = Ipv4Addr::new(192, 168, 1, 1);
+        let opts = udp::Ipv4Options { dscp: 10, ecn: 1, copy_inner_tos: true, ..Default::default() };
+        let inner_opts = udp::Ipv4Options { dscp: 46, ecn: 2, ..Default::default() };
+        let inner = udp::create_ipv4_udp_packet_with(b"voice", dst_ip, src_ip, 5000, 6000, &inner_opts);
+
+        let outer = udp::create_ipv4_udp_packet_with(&inner, src_ip, dst_ip, 1000, 2000, &opts);
+        assert_eq!(outer[1], 46 << 2 | 2);
+        assert_eq!(udp::checksum(&outer[..20]), 0);
+        // Same for the header of a gather write.
+        let mut hdr = [0u8; udp::MAX_HEADERS_LEN];
+        udp::encode_ipv4_udp_header_with(&mut hdr, &inner, src_ip, dst_ip, 1000, 2000, &opts).unwrap();
+        assert_eq!(hdr[1], 46 << 2 | 2);
+
+        // Opaque bytes, a short or corrupted header, or the mode off: the
+        // configured values.
+        let mut corrupt = inner.clone();
+        corrupt[10] ^= 0xFF;
+        let not_ip: [&[u8]; 4] = [b"opaque payload, no IP here", &inner[..12], &corrupt, b""];
+        for payload in not_ip {
+            let outer = udp::create_ipv4_udp_packet_with(payload, src_ip, dst_ip, 1000, 2000, &opts);
+            assert_eq!(outer[1], 10 << 2 | 1);
+        }
+        let opts = udp::Ipv4Options { copy_inner_tos: false, ..opts };
+        let outer = udp::create_ipv4_udp_packet_with(&inner, src_ip, dst_ip, 1000, 2000, &opts);
+        assert_eq!(outer[1], 10 << 2 | 1);
+    }
+
+    /// Cost of building a packet with and without allocating it.
+    /// Run with `cargo test --release encode_cost -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn encode_cost() {
+        use std::hint::black_box;
+        use std::time::Instant;
+
+        const ROUNDS: u32 = 1_000_000;
+        let src_ip = Ipv4Addr::new(192, 168, 1, 100);
+        let dst_ip = Ipv4Addr::new(192, 168, 1, 1);
+        let payload = [7u8; 1200];
+        let start = Instant::now();
+        for _ in 0..ROUNDS {
+            black_box(udp::create_ipv4_udp_packet(black_box(&payload), src_ip, dst_ip, 1000, 2000));
+        }
+        let allocating = start.elapsed() / ROUNDS;
+        let mut buf = vec![0u8; payload.len() + udp::MAX_HEADERS_LEN];
+        let start = Instant::now();
+        for _ in 0..ROUNDS {
+            black_box(udp::encode_ipv4_udp_into(&mut buf, black_box(&payload), src_ip, dst_ip, 1000, 2000).unwrap());
+        }
+        let in_place = start.elapsed() / ROUNDS;
+        println!("{} byte payload: {allocating:?} allocating, {in_place:?} in place", payload.len());
+    }
+
+    #[test]
+    fn reserved_flag_bit() {
+        let src_ip = Ipv4Addr::new(192, 168, 1, 100);
+        let dst_ip = Ipv4Addr::new(192, 168, 1, 1);
+        let mut packet = udp::create_ipv4_udp_packet(b"evil", src_ip, dst_ip, 1000, 2000);
+        packet[6] |= 0x80;
+        packet[10..12].copy_from_slice(&[0, 0]);
+        let csum = udp::checksum(&packet[..20]);
+        packet[10..12].copy_from_slice(&csum.to_be_bytes());
+
+        let strict = udp::ParseOptions {
+            reject_reserved_flag: true,
+            ..Default::default()
+        };
+        assert!(udp::parse_ipv4_udp_packet_with(&packet, &strict).is_err());
+        assert!(udp::parse_ipv4_udp_packet(&packet).is_ok());
+
+        // A clean packet passes the strict check.
+        let packet = udp::create_ipv4_udp_packet(b"good", src_ip, dst_ip, 1000, 2000);
+        assert!(udp::parse_ipv4_udp_packet_with(&packet, &strict).is_ok());
+    }
+
+    #[test]
+    fn strips_link_layer_headers() {
+        let src_ip = Ipv4Addr::new(192, 168, 1, 100);
+        let dst_ip = Ipv4Addr::new(192, 168, 1, 1);
+        let opts = udp::Ipv4Options {
+            dscp: 46,
+            ecn: 1,
+            ..Default::default()
+        };
+        let packet = udp::create_ipv4_udp_packet_with(b"framed", src_ip, dst_ip, 1000, 2000, &opts);
+        let mut eth = vec![0xaa; 12];
+        eth.extend_from_slice(&[0x08, 0x00]);
+        eth.extend_from_slice(&packet);
+        let mut vlan = vec![0xaa; 12];
+        vlan.extend_from_slice(&[0x81, 0x00, 0x00, 0x2a, 0x08, 0x00]);
+        vlan.extend_from_slice(&packet);
+
+        for (buf, link_layer) in [(&eth, udp::LinkLayer::Ethernet), (&vlan, udp::LinkLayer::EthernetVlan)] {
+            let opts = udp::ParseOptions {
+                link_layer,
+                ..Default::default()
+            };
+            let parsed = udp::parse_ipv4_udp_packet_with(buf, &opts).unwrap();
+            assert_eq!((parsed.src_ip, parsed.dst_ip), (src_ip, dst_ip));
+            assert_eq!(parsed.payload, b"framed");
+            // Read past the link layer header.
+            assert_eq!(parsed.tos, 0xB9);
+            // Raw IP parsing does not see an IPv4 header at offset 0.
+            assert!(udp::parse_ipv4_udp_packet(buf).is_err());
+        }
+
+        // Wrong EtherType, missing tag, truncated header.
+        let mut arp = eth.clone();
+        arp[12..14].copy_from_slice(&[0x08, 0x06]);
+        assert!(udp::strip_link_layer(&arp, udp::LinkLayer::Ethernet).is_none());
+        assert!(udp::strip_link_layer(&eth, udp::LinkLayer::EthernetVlan).is_none());
+        assert!(udp::strip_link_layer(&vlan[..15], udp::LinkLayer::EthernetVlan).is_none());
+    }
+
     #[test]
     fn example_encapsulate_decapsulate() {
         // other way.